@@ -0,0 +1,327 @@
+use crate::{bloom, cache::CacheStore};
+use parking_lot::{Mutex, RwLock};
+use std::{str::FromStr, sync::Arc};
+
+/// what is known about an upstream response when deciding
+/// whether to cache it
+pub struct Candidate<'a> {
+    pub uri: &'a str,
+    /// from Content-Length, if upstream sent one
+    pub size: Option<u64>,
+}
+
+/// decides which objects are worth caching
+pub trait Admission: Send + Sync {
+    /// called for every request for an object, hit or miss
+    fn observe(&self, _uri: &str) {}
+
+    fn admit(&self, candidate: &Candidate) -> bool;
+
+    /// asked instead of admit for objects being filled for a range
+    /// request, which are wanted already. only policies judging
+    /// the object itself get a say
+    fn admit_fill(&self, candidate: &Candidate) -> bool {
+        self.admit(candidate)
+    }
+}
+
+/// cache anything requested at least twice recently, as
+/// remembered by a bloom filter
+pub struct SecondHit(pub Arc<RwLock<bloom::Filter>>);
+
+impl Admission for SecondHit {
+    fn observe(&self, uri: &str) {
+        let uri = uri.as_bytes();
+        let fading = {
+            let filter = self.0.read();
+            filter.check(uri) && !filter.check_current(uri)
+        };
+        // keep things that are still being asked for from being
+        // forgotten on the next rotation
+        if fading {
+            self.0.write().add(uri);
+        }
+    }
+
+    fn admit(&self, candidate: &Candidate) -> bool {
+        let uri = candidate.uri.as_bytes();
+        if self.0.read().check(uri) {
+            return true;
+        }
+        self.0.write().add(uri);
+        false
+    }
+
+    fn admit_fill(&self, _candidate: &Candidate) -> bool {
+        true
+    }
+}
+
+pub struct Always;
+
+impl Admission for Always {
+    fn admit(&self, _candidate: &Candidate) -> bool {
+        true
+    }
+}
+
+/// cache anything known to be at most this many bytes
+pub struct MaxSize(pub u64);
+
+impl Admission for MaxSize {
+    fn admit(&self, candidate: &Candidate) -> bool {
+        candidate.size.is_some_and(|size| size <= self.0)
+    }
+}
+
+/// cache anything with a uri ending in one of these
+pub struct Extensions(pub Vec<String>);
+
+impl Admission for Extensions {
+    fn admit(&self, candidate: &Candidate) -> bool {
+        self.0
+            .iter()
+            .any(|ext| candidate.uri.ends_with(ext.as_str()))
+    }
+}
+
+/// approximate counts of how often things were requested, in a
+/// count-min sketch that is halved every so often so old
+/// popularity fades away
+pub struct Sketch {
+    rows: [Box<[u8]>; 4],
+    additions: usize,
+}
+
+impl Sketch {
+    /// counters saturate here, like the 4 bit counters in the paper
+    const MAX: u8 = 15;
+
+    pub fn new(width: usize) -> Self {
+        Self {
+            rows: std::array::from_fn(|_| vec![0; width].into()),
+            additions: 0,
+        }
+    }
+
+    /// counter index in each row for data
+    fn slots(&self, data: &[u8]) -> [usize; 4] {
+        let width = self.rows[0].len() as u64;
+        std::array::from_fn(|row| (bloom::hash(row as u64, data) % width) as usize)
+    }
+
+    pub fn add(&mut self, data: &[u8]) {
+        for (row, i) in self.slots(data).into_iter().enumerate() {
+            let counter = &mut self.rows[row][i];
+            *counter = (*counter + 1).min(Self::MAX);
+        }
+
+        self.additions += 1;
+        if self.additions >= self.rows[0].len() * 10 {
+            self.halve();
+        }
+    }
+
+    pub fn estimate(&self, data: &[u8]) -> u8 {
+        let slots = self.slots(data);
+        (0..4)
+            .map(|row| self.rows[row][slots[row]])
+            .min()
+            .unwrap_or(0)
+    }
+
+    fn halve(&mut self) {
+        for row in &mut self.rows {
+            for counter in row.iter_mut() {
+                *counter >>= 1;
+            }
+        }
+        self.additions /= 2;
+    }
+}
+
+/// like tinylfu, only cache something if it is requested more
+/// often than whatever would be evicted to make room for it
+pub struct TinyLfu {
+    sketch: Mutex<Sketch>,
+    cachestore: Arc<CacheStore>,
+}
+
+impl TinyLfu {
+    pub fn new(cachestore: Arc<CacheStore>) -> Self {
+        Self {
+            sketch: Mutex::new(Sketch::new(4096)),
+            cachestore,
+        }
+    }
+}
+
+impl Admission for TinyLfu {
+    fn observe(&self, uri: &str) {
+        self.sketch.lock().add(uri.as_bytes());
+    }
+
+    fn admit(&self, candidate: &Candidate) -> bool {
+        let size = candidate.size.unwrap_or(0) as usize;
+        let Some(victim) = self.cachestore.victim(size) else {
+            // there is still room, no need to be picky
+            return true;
+        };
+
+        let sketch = self.sketch.lock();
+        sketch.estimate(candidate.uri.as_bytes()) > sketch.estimate(victim.as_bytes())
+    }
+
+    fn admit_fill(&self, _candidate: &Candidate) -> bool {
+        true
+    }
+}
+
+/// admission policy as picked from the command line
+#[derive(Clone, Debug, PartialEq)]
+pub enum Policy {
+    SecondHit,
+    Always,
+    MaxSize(u64),
+    Extensions(Vec<String>),
+    TinyLfu,
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, arg) = match s.split_once(':') {
+            Some((name, arg)) => (name, Some(arg)),
+            None => (s, None),
+        };
+
+        match (name, arg) {
+            ("second-hit", None) => Ok(Self::SecondHit),
+            ("always", None) => Ok(Self::Always),
+            ("size", Some(max)) => max.parse().map(Self::MaxSize).map_err(|e| e.to_string()),
+            ("ext", None) => Ok(Self::Extensions(
+                [".tcz", ".tcz.dep", ".tcz.md5.txt"]
+                    .map(str::to_string)
+                    .to_vec(),
+            )),
+            ("ext", Some(exts)) => Ok(Self::Extensions(
+                exts.split(',').map(str::to_string).collect(),
+            )),
+            ("tinylfu", None) => Ok(Self::TinyLfu),
+            _ => Err(
+                "expected one of second-hit, always, size:BYTES, ext[:EXT,...] or tinylfu"
+                    .to_string(),
+            ),
+        }
+    }
+}
+
+impl Policy {
+    pub fn build(
+        &self,
+        filter: &Arc<RwLock<bloom::Filter>>,
+        cachestore: &Arc<CacheStore>,
+    ) -> Box<dyn Admission> {
+        match self {
+            Self::SecondHit => Box::new(SecondHit(Arc::clone(filter))),
+            Self::Always => Box::new(Always),
+            Self::MaxSize(max) => Box::new(MaxSize(*max)),
+            Self::Extensions(exts) => Box::new(Extensions(exts.clone())),
+            Self::TinyLfu => Box::new(TinyLfu::new(Arc::clone(cachestore))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::admission::*;
+    use crate::{cache::Meta, metrics::Metrics};
+    use hyper::body::Bytes;
+
+    fn candidate(uri: &str, size: Option<u64>) -> Candidate<'_> {
+        Candidate { uri, size }
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!("second-hit".parse(), Ok(Policy::SecondHit));
+        assert_eq!("size:1024".parse(), Ok(Policy::MaxSize(1024)));
+        assert_eq!(
+            "ext:.tcz,.dep".parse(),
+            Ok(Policy::Extensions(vec![".tcz".into(), ".dep".into()]))
+        );
+        assert!("size".parse::<Policy>().is_err());
+        assert!("size:lots".parse::<Policy>().is_err());
+        assert!("sometimes".parse::<Policy>().is_err());
+    }
+
+    #[test]
+    fn second_hit() {
+        let policy = SecondHit(Arc::new(RwLock::new(bloom::Filter::default())));
+        assert!(!policy.admit(&candidate("/a", None)));
+        assert!(policy.admit(&candidate("/a", None)));
+        assert!(!policy.admit(&candidate("/b", None)));
+        // wanted enough already
+        assert!(policy.admit_fill(&candidate("/c", None)));
+    }
+
+    #[test]
+    fn simple() {
+        assert!(Always.admit(&candidate("/a", None)));
+
+        assert!(MaxSize(3).admit(&candidate("/a", Some(3))));
+        assert!(!MaxSize(3).admit(&candidate("/a", Some(4))));
+        assert!(!MaxSize(3).admit(&candidate("/a", None)));
+        assert!(!MaxSize(3).admit_fill(&candidate("/a", Some(4))));
+
+        let Ok(Policy::Extensions(exts)) = "ext".parse() else {
+            panic!("ext should parse");
+        };
+        let policy = Extensions(exts);
+        assert!(policy.admit(&candidate("/sed.tcz", None)));
+        assert!(policy.admit(&candidate("/sed.tcz.dep", None)));
+        assert!(!policy.admit(&candidate("/sed.tcz.info", None)));
+        assert!(!policy.admit_fill(&candidate("/sed.tcz.info", None)));
+    }
+
+    #[test]
+    fn sketch() {
+        let mut sketch = Sketch::new(64);
+        for _ in 0..5 {
+            sketch.add(b"yip");
+        }
+        sketch.add(b"yap");
+        assert_eq!(sketch.estimate(b"yip"), 5);
+        assert!(sketch.estimate(b"yap") >= 1);
+
+        sketch.halve();
+        assert_eq!(sketch.estimate(b"yip"), 2);
+    }
+
+    #[test]
+    fn tinylfu() {
+        let cachestore = CacheStore::new(None, Some(3), None, Metrics::new());
+        let policy = TinyLfu::new(Arc::clone(&cachestore));
+
+        // plenty of room
+        policy.observe("/a");
+        assert!(policy.admit(&candidate("/a", Some(3))));
+        cachestore.insert(
+            "/a".to_string(),
+            Bytes::from_static(b"yip"),
+            Meta::default(),
+        );
+        policy.observe("/a");
+
+        // would evict the more popular /a
+        policy.observe("/b");
+        assert!(!policy.admit(&candidate("/b", Some(3))));
+        assert!(policy.admit_fill(&candidate("/b", Some(3))));
+
+        for _ in 0..3 {
+            policy.observe("/b");
+        }
+        assert!(policy.admit(&candidate("/b", Some(3))));
+    }
+}
@@ -1,44 +1,160 @@
-#[allow(clippy::precedence)]
-pub fn hash(state: u16, data: &[u8]) -> u16 {
-    let mut state = state;
+use std::{fs, io, path::Path};
+
+/// first line of a saved filter, along with the size and hashes it
+/// was made with. bump the version whenever the format changes
+const MAGIC: &str = "tcrelay bloom 1";
+
+/// fnv-1a, finished off like splitmix64 since fnv alone leaves
+/// the high bits poorly mixed
+pub fn hash(seed: u64, data: &[u8]) -> u64 {
+    let mut state = 0xcbf2_9ce4_8422_2325 ^ seed;
 
     for c in data {
-        let mut x: u16 = state >> 8 ^ u16::from(*c);
-        x ^= x >> 4;
-        state = state << 8 ^ x << 12 ^ x << 5 ^ x;
+        state ^= u64::from(*c);
+        state = state.wrapping_mul(0x0100_0000_01b3);
     }
 
-    state
+    state ^= state >> 30;
+    state = state.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    state ^= state >> 27;
+    state = state.wrapping_mul(0x94d0_49bb_1331_11eb);
+    state ^ state >> 31
+}
+
+/// bit locations for data in a filter of bits bits, derived
+/// from two hashes rather than computing one per location
+fn locations(bits: u64, hashes: u32, data: &[u8]) -> impl Iterator<Item = usize> {
+    let h1 = hash(0, data);
+    let h2 = hash(1, data) | 1;
+
+    (0..u64::from(hashes)).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bits) as usize)
+}
+
+pub fn add(filter: &mut [u8], hashes: u32, data: &[u8]) {
+    for loc in locations(filter.len() as u64 * 8, hashes, data) {
+        filter[loc >> 3] |= 1 << (loc & 7);
+    }
 }
 
-macro_rules! make_add {
-    ($filter:expr, $data:expr, $($init:expr),*) => {
-        $({
-            let loc = hash($init, $data) as usize;
-            $filter[loc >> 3] |= 1 << (loc & 7);
-        })*
-    };
+pub fn check(filter: &[u8], hashes: u32, data: &[u8]) -> bool {
+    locations(filter.len() as u64 * 8, hashes, data)
+        .all(|loc| filter[loc >> 3] & 1 << (loc & 7) != 0)
 }
 
-pub fn add(filter: &mut [u8; 8192], data: &[u8]) {
-    make_add!(filter, data, 3, 6, 2, 1);
+/// fraction of bits set in filter
+pub fn fill(filter: &[u8]) -> f64 {
+    let set: u64 = filter.iter().map(|b| u64::from(b.count_ones())).sum();
+    set as f64 / (filter.len() as f64 * 8.0)
 }
 
-macro_rules! make_check {
-    ($filter:expr, $data:expr, $init:expr) => {
-        {
-            let loc = hash($init, $data) as usize;
-            ($filter[loc >> 3] & 1 << (loc & 7)) != 0
+/// two generations of filters, so things stop being seen once
+/// they have not come up for a while
+///
+/// additions only go to the current generation, while checks
+/// look at both. rotating forgets the previous generation and
+/// starts a fresh current one
+pub struct Filter {
+    current: Box<[u8]>,
+    previous: Box<[u8]>,
+    hashes: u32,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self::new(8192, 4)
+    }
+}
+
+impl Filter {
+    /// create a filter using size bytes per generation, setting
+    /// hashes bits for everything added
+    pub fn new(size: usize, hashes: u32) -> Self {
+        Self {
+            current: vec![0; size].into(),
+            previous: vec![0; size].into(),
+            hashes,
         }
-    };
-    ($filter:expr, $data:expr, $init:expr, $($tail:tt)*) => {
-        make_check!($filter, $data, $init) &&
-            make_check!($filter, $data, $($tail)*)
+    }
+
+    pub fn add(&mut self, data: &[u8]) {
+        add(&mut self.current, self.hashes, data);
+    }
+
+    pub fn check(&self, data: &[u8]) -> bool {
+        check(&self.current, self.hashes, data) || check(&self.previous, self.hashes, data)
+    }
+
+    /// if data would survive the next rotation
+    pub fn check_current(&self, data: &[u8]) -> bool {
+        check(&self.current, self.hashes, data)
+    }
+
+    pub fn rotate(&mut self) {
+        let fresh = vec![0; self.current.len()].into();
+        self.previous = std::mem::replace(&mut self.current, fresh);
+    }
+
+    /// fraction of bits set in the current generation, and the
+    /// estimated chance of check returning true for something
+    /// never added
+    pub fn stats(&self) -> (f64, f64) {
+        let current = fill(&self.current);
+        let previous = fill(&self.previous);
+        let k = self.hashes as i32;
+
+        // a false positive in either generation is enough
+        let fpr = 1.0 - (1.0 - current.powi(k)) * (1.0 - previous.powi(k));
+
+        (current, fpr)
+    }
+
+    /// serialize both generations, to be read back by from_bytes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = format!("{MAGIC} {} {}\n", self.current.len(), self.hashes).into_bytes();
+        out.extend_from_slice(&self.current);
+        out.extend_from_slice(&self.previous);
+        out
+    }
+
+    /// read back a filter from to_bytes, only if it was made with
+    /// the same size and hashes
+    pub fn from_bytes(data: &[u8], size: usize, hashes: u32) -> Option<Self> {
+        let data = data.strip_prefix(format!("{MAGIC} {size} {hashes}\n").as_bytes())?;
+        if data.len() != size * 2 {
+            return None;
+        }
+
+        let (current, previous) = data.split_at(size);
+        Some(Self {
+            current: current.into(),
+            previous: previous.into(),
+            hashes,
+        })
+    }
+
+    pub fn load(path: &Path, size: usize, hashes: u32) -> io::Result<Self> {
+        Self::from_bytes(&fs::read(path)?, size, hashes).ok_or(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "saved with a different format, size or hashes",
+        ))
     }
 }
 
-pub fn check(filter: &[u8; 8192], data: &[u8]) -> bool {
-    make_check!(filter, data, 3, 6, 2, 1)
+/// write out data from to_bytes, replacing whatever was at path
+/// only once it is complete
+pub fn save(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+
+    let res = (|| {
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, path)
+    })();
+
+    if res.is_err() {
+        _ = fs::remove_file(&tmp);
+    }
+    res
 }
 
 #[cfg(test)]
@@ -48,27 +164,99 @@ mod tests {
     #[test]
     fn hash_literal() {
         let out = hash(0, b"meow im a fox");
-        assert_eq!(out, 29020);
+        assert_eq!(out, 0xcb1b_2a9b_ae9c_ef69);
     }
 
     #[test]
     fn added() {
         let mut filter = [0_u8; 8192];
-        add(&mut filter, b"yip");
-        add(&mut filter, b"yap");
-        add(&mut filter, b"yop");
+        add(&mut filter, 4, b"yip");
+        add(&mut filter, 4, b"yap");
+        add(&mut filter, 4, b"yop");
 
-        assert!(check(&filter, b"yap"));
+        assert!(check(&filter, 4, b"yap"));
 
         // no awoo, $300 fine
-        assert!(!check(&filter, b"awoo"));
+        assert!(!check(&filter, 4, b"awoo"));
     }
 
     #[test]
     fn all_ones() {
         let filter = [255_u8; 8192];
 
-        assert!(check(&filter, b"beep"));
-        assert!(check(&filter, b"boop"));
+        assert!(check(&filter, 4, b"beep"));
+        assert!(check(&filter, 4, b"boop"));
+    }
+
+    #[test]
+    fn big_filter() {
+        // far larger than a 16 bit hash could ever address
+        let mut filter = vec![0_u8; 1 << 20];
+        add(&mut filter, 7, b"yip");
+
+        let set: Vec<_> = filter
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| **b != 0)
+            .collect();
+        assert!(set.iter().any(|(i, _)| *i >= 8192));
+        assert!(check(&filter, 7, b"yip"));
+        assert!(!check(&filter, 7, b"yap"));
+    }
+
+    #[test]
+    fn generations() {
+        let mut filter = Filter::default();
+        filter.add(b"yip");
+        filter.rotate();
+        filter.add(b"yap");
+
+        assert!(filter.check(b"yip"));
+        assert!(!filter.check_current(b"yip"));
+        assert!(filter.check_current(b"yap"));
+
+        filter.rotate();
+        assert!(!filter.check(b"yip"));
+        assert!(filter.check(b"yap"));
+    }
+
+    #[test]
+    fn stats() {
+        let mut filter = Filter::new(1, 1);
+        assert_eq!(filter.stats(), (0.0, 0.0));
+
+        filter.current.fill(0b0000_1111);
+        assert_eq!(filter.stats(), (0.5, 0.5));
+
+        filter.rotate();
+        filter.current.fill(0b0000_1111);
+        assert_eq!(filter.stats(), (0.5, 0.75));
+    }
+
+    #[test]
+    fn persist() {
+        let mut filter = Filter::new(64, 3);
+        filter.add(b"yip");
+        filter.rotate();
+        filter.add(b"yap");
+
+        let path = std::env::temp_dir().join(format!("tcrelay-bloom-{}", std::process::id()));
+        save(&path, &filter.to_bytes()).unwrap();
+
+        let loaded = Filter::load(&path, 64, 3).unwrap();
+        assert!(loaded.check(b"yip"));
+        assert!(!loaded.check_current(b"yip"));
+        assert!(loaded.check_current(b"yap"));
+        assert!(!loaded.check(b"awoo"));
+
+        // reconfigured since
+        assert!(Filter::load(&path, 128, 3).is_err());
+        assert!(Filter::load(&path, 64, 4).is_err());
+        fs::remove_file(&path).unwrap();
+
+        let mut data = filter.to_bytes();
+        data.pop();
+        assert!(Filter::from_bytes(&data, 64, 3).is_none());
+        assert!(Filter::from_bytes(b"tcrelay bloom 0 64 3\n", 64, 3).is_none());
     }
 }
@@ -0,0 +1,248 @@
+use super::Meta;
+use hyper::{
+    body::Bytes,
+    header::{HeaderName, HeaderValue},
+};
+use std::{
+    fs,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering::Relaxed},
+    time::{Duration, SystemTime},
+};
+
+/// first line of every object stored on disk, bump the version
+/// whenever the format changes so old objects are not misread
+const MAGIC: &[u8] = b"tcrelay 1\n";
+
+/// escape a uri into something usable as a single file name
+///
+/// the inverse of unescape
+pub fn filename(uri: &str) -> String {
+    let mut out = String::with_capacity(uri.len());
+    for c in uri.bytes() {
+        if c.is_ascii_alphanumeric() || b"-_.".contains(&c) {
+            out.push(c as char);
+        } else {
+            out.push_str(&format!("%{c:02X}"));
+        }
+    }
+    out
+}
+
+pub fn unescape(name: &str) -> Option<String> {
+    let mut out = Vec::with_capacity(name.len());
+    let mut bytes = name.bytes();
+    while let Some(c) = bytes.next() {
+        if c == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            let hex = std::str::from_utf8(&hex).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            out.push(c);
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+/// write an object to a temporary file, to be moved into place
+/// with commit
+/// somewhere in dir to put a file that is not an object yet, or
+/// not one anymore
+pub fn tmp_path(dir: &Path) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    // escaped file names never start with a dot, so these cannot
+    // clash with a real object
+    dir.join(format!(
+        ".tmp{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Relaxed)
+    ))
+}
+
+pub fn write_object(
+    dir: &Path,
+    content: &[u8],
+    meta: &Meta,
+    inserted: SystemTime,
+    etag: &str,
+) -> io::Result<PathBuf> {
+    let tmp = tmp_path(dir);
+
+    let mut header = MAGIC.to_vec();
+    header.extend(format!("size: {}\n", content.len()).bytes());
+    if let Ok(inserted) = inserted.duration_since(SystemTime::UNIX_EPOCH) {
+        header.extend(format!("inserted: {}\n", inserted.as_secs()).bytes());
+    }
+    header.extend(format!("strong-etag: {etag}\n").bytes());
+    if let Some(mirror) = meta.mirror {
+        header.extend(format!("mirror: {mirror}\n").bytes());
+    }
+    if let Some(ref etag) = meta.etag {
+        header.extend(format!("etag: {etag}\n").bytes());
+    }
+    if let Some(ref last_modified) = meta.last_modified {
+        header.extend(format!("last-modified: {last_modified}\n").bytes());
+    }
+    for (name, value) in &meta.headers {
+        if let Ok(value) = value.to_str() {
+            header.extend(format!("header: {name}: {value}\n").bytes());
+        }
+    }
+    header.push(b'\n');
+
+    let res = (|| {
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(&header)?;
+        file.write_all(content)?;
+        file.sync_all()
+    })();
+
+    match res {
+        Ok(()) => Ok(tmp),
+        Err(e) => {
+            _ = fs::remove_file(&tmp);
+            Err(e)
+        }
+    }
+}
+
+/// move an object written by write_object into place for uri
+pub fn commit(dir: &Path, uri: &str, tmp: &Path) -> io::Result<()> {
+    let res = fs::rename(tmp, dir.join(filename(uri)));
+    if res.is_err() {
+        _ = fs::remove_file(tmp);
+    }
+    res
+}
+
+pub fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[derive(Debug)]
+pub struct Header {
+    pub size: usize,
+    pub inserted: Option<SystemTime>,
+    /// the entity tag we hand out, which may differ from upstream
+    pub etag: Option<String>,
+    pub meta: Meta,
+    /// where the content starts, after the header
+    pub start: usize,
+}
+
+pub fn read_header(mut reader: impl BufRead) -> io::Result<Header> {
+    let mut line = Vec::new();
+    let mut start = reader.read_until(b'\n', &mut line)?;
+    if line != MAGIC {
+        return Err(invalid("unknown format"));
+    }
+
+    let mut size = None;
+    let mut inserted = None;
+    let mut etag = None;
+    let mut meta = Meta::default();
+    loop {
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line)?;
+        if read == 0 {
+            return Err(invalid("unterminated header"));
+        }
+        start += read;
+
+        if line == b"\n" {
+            break;
+        }
+        let Some((key, value)) = std::str::from_utf8(&line)
+            .ok()
+            .and_then(|l| l.trim_end().split_once(": "))
+        else {
+            return Err(invalid("mangled header"));
+        };
+        match key {
+            "size" => size = value.parse().ok(),
+            "inserted" => {
+                inserted = value
+                    .parse()
+                    .ok()
+                    .map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+            }
+            "strong-etag" => etag = Some(value.to_string()),
+            "mirror" => meta.mirror = value.parse().ok(),
+            "etag" => meta.etag = Some(value.to_string()),
+            "last-modified" => meta.last_modified = Some(value.to_string()),
+            "header" => {
+                let parsed = value.split_once(": ").and_then(|(name, value)| {
+                    Some((
+                        HeaderName::from_bytes(name.as_bytes()).ok()?,
+                        HeaderValue::from_str(value).ok()?,
+                    ))
+                });
+                if let Some((name, value)) = parsed {
+                    meta.headers.append(name, value);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    Ok(Header {
+        size: size.ok_or(invalid("missing size"))?,
+        inserted,
+        etag,
+        meta,
+        start,
+    })
+}
+
+pub fn read_object(path: &Path) -> io::Result<Bytes> {
+    let data = Bytes::from(fs::read(path)?);
+    let header = read_header(&data[..])?;
+    if data.len() - header.start != header.size {
+        return Err(invalid("truncated object"));
+    }
+
+    Ok(data.slice(header.start..))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::disk::*;
+
+    #[test]
+    fn escaped_filename() {
+        assert_eq!(
+            filename("/10.x/x86/tcz/sed.tcz"),
+            "%2F10.x%2Fx86%2Ftcz%2Fsed.tcz"
+        );
+        assert_eq!(filename("/../yip yap"), "%2F..%2Fyip%20yap");
+        assert_eq!(unescape("%2F..%2Fyip%20yap").unwrap(), "/../yip yap");
+        assert_eq!(unescape("%2"), None);
+    }
+
+    #[test]
+    fn header() {
+        let inp = b"tcrelay 1\nsize: 3\ninserted: 60\nstrong-etag: \"60-3\"\nmirror: 2\netag: \"fox\"\nlast-modified: Tue, 15 Nov 1994 08:12:31 GMT\nheader: content-type: text/plain\nheader: bogus\n\nyip";
+        let header = read_header(&inp[..]).unwrap();
+        assert_eq!(header.size, 3);
+        assert_eq!(
+            header.inserted.unwrap(),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(60)
+        );
+        assert_eq!(header.etag.unwrap(), "\"60-3\"");
+        assert_eq!(header.meta.mirror, Some(2));
+        assert_eq!(header.start, inp.len() - 3);
+        assert_eq!(header.meta.headers["content-type"], "text/plain");
+        assert_eq!(header.meta.headers.len(), 1);
+        assert_eq!(header.meta.etag.unwrap(), "\"fox\"");
+        assert_eq!(
+            header.meta.last_modified.unwrap(),
+            "Tue, 15 Nov 1994 08:12:31 GMT"
+        );
+
+        read_header(&b"tcrelay 0\nsize: 3\n\nyip"[..]).unwrap_err();
+        read_header(&b"tcrelay 1\nsize: 3\nyip"[..]).unwrap_err();
+        read_header(&b"tcrelay 1\netag: \"fox\"\n\nyip"[..]).unwrap_err();
+    }
+}
@@ -0,0 +1,159 @@
+use super::{disk, CacheStore, Listing};
+use http_body_util::{combinators::BoxBody, BodyExt, Full};
+use hyper::{body::Bytes, Response};
+use std::{fmt::Write, time::SystemTime};
+
+/// find the value of key in a query string
+fn query_param(query: Option<&str>, key: &str) -> Option<String> {
+    query?
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(k, _)| *k == key)
+        .and_then(|(_, v)| disk::unescape(v))
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => _ = write!(out, "\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+pub fn text(listing: &[Listing]) -> String {
+    let mut out = String::from("# uri size inserted mirror hits\n");
+    for l in listing {
+        let mirror = l.mirror.map_or("-".to_string(), |m| m.to_string());
+        _ = writeln!(
+            out,
+            "{} {} {} {} {}",
+            l.uri,
+            l.size,
+            unix_secs(l.inserted),
+            mirror,
+            l.hits
+        );
+    }
+    out
+}
+
+pub fn json(listing: &[Listing]) -> String {
+    let mut out = String::from("[");
+    for (i, l) in listing.iter().enumerate() {
+        if i != 0 {
+            out.push(',');
+        }
+        out.push_str("{\"uri\":");
+        json_string(&mut out, &l.uri);
+        let mirror = l.mirror.map_or("null".to_string(), |m| m.to_string());
+        _ = write!(
+            out,
+            ",\"size\":{},\"inserted\":{},\"mirror\":{},\"hits\":{}}}",
+            l.size,
+            unix_secs(l.inserted),
+            mirror,
+            l.hits
+        );
+    }
+    out.push_str("]\n");
+    out
+}
+
+impl CacheStore {
+    /// list the cache, optionally filtered with a prefix= query
+    /// and as json with a format=json query
+    pub fn response(&self, query: Option<&str>) -> Response<BoxBody<Bytes, crate::hclient::Error>> {
+        let prefix = query_param(query, "prefix").unwrap_or_default();
+        let listing = self.list(&prefix);
+
+        let (body, content_type) = if query_param(query, "format").as_deref() == Some("json") {
+            (json(&listing), "application/json")
+        } else {
+            (text(&listing), "text/plain")
+        };
+
+        let mut res = Response::new(Full::new(Bytes::from(body)).map_err(|e| match e {}).boxed());
+        res.headers_mut().insert(
+            hyper::header::CONTENT_TYPE,
+            hyper::header::HeaderValue::from_static(content_type),
+        );
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::{listing::*, Meta};
+    use crate::metrics::Metrics;
+    use std::time::Duration;
+
+    #[test]
+    fn params() {
+        let query = Some("format=json&prefix=%2F15.x%2Fx86_64");
+        assert_eq!(query_param(query, "format").unwrap(), "json");
+        assert_eq!(query_param(query, "prefix").unwrap(), "/15.x/x86_64");
+        assert_eq!(query_param(query, "awoo"), None);
+        assert_eq!(query_param(None, "format"), None);
+    }
+
+    #[test]
+    fn formats() {
+        let listing = [
+            Listing {
+                uri: "/a \"fox\"".to_string(),
+                size: 3,
+                inserted: SystemTime::UNIX_EPOCH + Duration::from_secs(60),
+                mirror: Some(1),
+                hits: 4,
+            },
+            Listing {
+                uri: "/b".to_string(),
+                size: 5,
+                inserted: SystemTime::UNIX_EPOCH,
+                mirror: None,
+                hits: 0,
+            },
+        ];
+
+        assert_eq!(
+            text(&listing),
+            "# uri size inserted mirror hits\n/a \"fox\" 3 60 1 4\n/b 5 0 - 0\n"
+        );
+        assert_eq!(
+            json(&listing),
+            r#"[{"uri":"/a \"fox\"","size":3,"inserted":60,"mirror":1,"hits":4},{"uri":"/b","size":5,"inserted":0,"mirror":null,"hits":0}]
+"#
+        );
+    }
+
+    #[tokio::test]
+    async fn prefix() {
+        let cachestore = CacheStore::new(None, None, None, Metrics::new());
+        for uri in [
+            "/14.x/x86/tcz/a.tcz",
+            "/15.x/x86/tcz/b.tcz",
+            "/15.x/x86_64/tcz/c.tcz",
+        ] {
+            cachestore.insert(uri.to_string(), Bytes::from_static(b"yip"), Meta::default());
+        }
+        cachestore.get("/15.x/x86/tcz/b.tcz").await;
+
+        let listing = cachestore.list("/15.x/x86/");
+        assert_eq!(listing.len(), 1);
+        assert_eq!(listing[0].uri, "/15.x/x86/tcz/b.tcz");
+        assert_eq!(listing[0].hits, 1);
+
+        assert_eq!(cachestore.list("/15.x/").len(), 2);
+        assert_eq!(cachestore.list("").len(), 3);
+    }
+}
@@ -0,0 +1,1031 @@
+use crate::{
+    conditional::{self, Validators},
+    hclient::Error,
+    metrics::Metrics,
+    verify::Verifier,
+};
+use hyper::{
+    body::{Body, Bytes, Frame, SizeHint},
+    header::{self, HeaderMap, HeaderValue},
+};
+use parking_lot::Mutex;
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, BufReader},
+    marker::Unpin,
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+mod disk;
+mod listing;
+
+/// details about where an object came from, kept alongside it
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Meta {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// index of the mirror it was fetched from
+    pub mirror: Option<usize>,
+    /// upstream response headers to pass on to clients
+    pub headers: HeaderMap,
+}
+
+impl Meta {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let get = |name| {
+            headers
+                .get(name)
+                .and_then(|v: &HeaderValue| v.to_str().ok())
+                .map(str::to_string)
+        };
+        Self {
+            etag: get(header::ETAG),
+            last_modified: get(header::LAST_MODIFIED),
+            mirror: None,
+            headers: HeaderMap::new(),
+        }
+    }
+
+    /// headers for asking upstream if the object has changed
+    pub fn conditions(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let mut set = |name, value: &Option<String>| {
+            if let Some(v) = value.as_deref().and_then(|v| HeaderValue::from_str(v).ok()) {
+                headers.insert(name, v);
+            }
+        };
+        set(header::IF_NONE_MATCH, &self.etag);
+        set(header::IF_MODIFIED_SINCE, &self.last_modified);
+        headers
+    }
+}
+
+/// summary of a cached object, for listing what is cached
+#[derive(Debug, PartialEq)]
+pub struct Listing {
+    pub uri: String,
+    pub size: usize,
+    pub inserted: SystemTime,
+    pub mirror: Option<usize>,
+    pub hits: u64,
+}
+
+pub struct Object {
+    pub content: Bytes,
+    pub meta: Meta,
+    pub validators: Validators,
+    /// if the object has outlived its freshness lifetime
+    /// and should be revalidated with upstream
+    pub stale: bool,
+}
+
+pub struct Head {
+    pub size: usize,
+    pub validators: Validators,
+    pub headers: HeaderMap,
+    pub stale: bool,
+}
+
+struct Entry {
+    /// only kept in memory when there is no cache directory
+    content: Option<Bytes>,
+    meta: Meta,
+    /// strong entity tag handed out to clients
+    etag: String,
+    size: usize,
+    inserted: SystemTime,
+    fetched: SystemTime,
+    hits: u64,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Store {
+    entries: BTreeMap<String, Entry>,
+    /// uris keyed by when they were last used, oldest first
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+    size: usize,
+}
+
+impl Store {
+    fn touch(&mut self, uri: &str) -> Option<&mut Entry> {
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.entries.get_mut(uri)?;
+
+        self.recency.remove(&entry.last_used);
+        self.recency.insert(tick, uri.to_string());
+        entry.last_used = tick;
+
+        Some(entry)
+    }
+
+    fn insert(&mut self, uri: String, mut entry: Entry) {
+        self.tick += 1;
+        entry.last_used = self.tick;
+        self.recency.insert(entry.last_used, uri.clone());
+        self.size += entry.size;
+        self.entries.insert(uri, entry);
+    }
+
+    fn remove(&mut self, uri: &str) -> Option<usize> {
+        let entry = self.entries.remove(uri)?;
+        self.recency.remove(&entry.last_used);
+        self.size -= entry.size;
+        Some(entry.size)
+    }
+
+    fn evict(&mut self) -> Option<String> {
+        let (_, uri) = self.recency.pop_first()?;
+        let entry = self.entries.remove(&uri).expect("recency out of sync");
+        self.size -= entry.size;
+
+        #[cfg(feature = "log")]
+        eprintln!("evicted {} freeing {} B", uri, entry.size);
+
+        Some(uri)
+    }
+}
+
+pub struct CacheStore {
+    /// shared with whatever is unlinking evicted objects
+    store: Arc<Mutex<Store>>,
+    dir: Option<PathBuf>,
+    max_size: Option<usize>,
+    lifetime: Option<Duration>,
+    metrics: Arc<Metrics>,
+}
+
+impl CacheStore {
+    /// create a new cache, evicting the least recently used
+    /// objects once it would grow beyond max_size bytes
+    ///
+    /// objects are kept as files in dir if given, otherwise
+    /// they are only kept in memory. objects older than
+    /// lifetime are considered stale, or never if unset
+    pub fn new(
+        dir: Option<PathBuf>,
+        max_size: Option<usize>,
+        lifetime: Option<Duration>,
+        metrics: Arc<Metrics>,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            store: Arc::default(),
+            dir,
+            max_size,
+            lifetime,
+            metrics,
+        })
+    }
+
+    /// if objects are written to disk, and thus should be inserted
+    /// from somewhere that is allowed to block
+    pub fn on_disk(&self) -> bool {
+        self.dir.is_some()
+    }
+
+    /// what is known about an object without reading it, for
+    /// answering HEAD requests
+    ///
+    /// unlike get, this does not count as using the object
+    pub fn head(&self, uri: &str) -> Option<Head> {
+        let store = self.store.lock();
+        let entry = store.entries.get(uri)?;
+        Some(Head {
+            size: entry.size,
+            validators: Validators::new(
+                Some(&entry.etag),
+                entry.meta.last_modified.as_deref(),
+                entry.inserted,
+                entry.size,
+            ),
+            headers: entry.meta.headers.clone(),
+            stale: self.is_stale(entry.fetched),
+        })
+    }
+
+    fn is_stale(&self, fetched: SystemTime) -> bool {
+        self.lifetime
+            .is_some_and(|l| fetched.elapsed().map_or(true, |age| age >= l))
+    }
+
+    /// the object cached for uri, reading it from disk on a thread
+    /// that is allowed to block if needed
+    pub async fn get(&self, uri: &str) -> Option<Object> {
+        let (content, meta, validators, fetched) = {
+            let mut store = self.store.lock();
+            let entry = store.touch(uri)?;
+            entry.hits += 1;
+            let validators = Validators::new(
+                Some(&entry.etag),
+                entry.meta.last_modified.as_deref(),
+                entry.inserted,
+                entry.size,
+            );
+            (
+                entry.content.clone(),
+                entry.meta.clone(),
+                validators,
+                entry.fetched,
+            )
+        };
+        let stale = self.is_stale(fetched);
+
+        let content = match content {
+            Some(content) => content,
+            None => {
+                let path = self.dir.as_ref()?.join(disk::filename(uri));
+                let res = tokio::task::spawn_blocking(move || disk::read_object(&path))
+                    .await
+                    .unwrap_or_else(|e| Err(io::Error::other(e)));
+                match res {
+                    Ok(content) => content,
+                    Err(_e) => {
+                        #[cfg(feature = "log")]
+                        eprintln!("failed to read {} from disk: {:?}", uri, _e);
+
+                        self.store.lock().remove(uri);
+                        return None;
+                    }
+                }
+            }
+        };
+
+        Some(Object {
+            content,
+            meta,
+            validators,
+            stale,
+        })
+    }
+
+    pub fn insert(&self, uri: String, content: Bytes, meta: Meta) {
+        let size = content.len();
+        if self.max_size.is_some_and(|max| size > max) {
+            #[cfg(feature = "log")]
+            eprintln!("not caching {} since {} B will never fit", uri, size);
+
+            return;
+        }
+
+        let now = SystemTime::now();
+        let etag = Validators::new(meta.etag.as_deref(), None, now, size).etag;
+
+        let (content, tmp) = match self.dir {
+            Some(ref dir) => match disk::write_object(dir, &content, &meta, now, &etag) {
+                Ok(tmp) => (None, Some(tmp)),
+                Err(_e) => {
+                    #[cfg(feature = "log")]
+                    eprintln!("failed to write {} to disk: {:?}", uri, _e);
+
+                    return;
+                }
+            },
+            None => (Some(content), None),
+        };
+
+        let mut store = self.store.lock();
+        // only moved into place with the lock held, so a concurrent
+        // remove cannot unlink it from under the new entry
+        if let (Some(dir), Some(tmp)) = (&self.dir, tmp) {
+            if let Err(_e) = disk::commit(dir, &uri, &tmp) {
+                #[cfg(feature = "log")]
+                eprintln!("failed to write {} to disk: {:?}", uri, _e);
+
+                return;
+            }
+        }
+
+        #[cfg(feature = "log")]
+        eprintln!("cached {} using {} B", uri, size);
+
+        store.remove(&uri);
+
+        let evicted = self.shrink(&mut store, size);
+        store.insert(
+            uri,
+            Entry {
+                content,
+                meta,
+                etag,
+                size,
+                inserted: now,
+                fetched: now,
+                hits: 0,
+                last_used: 0,
+            },
+        );
+        drop(store);
+        self.unlink(evicted);
+    }
+
+    /// insert from an async context, moving disk writes somewhere
+    /// they are allowed to block
+    pub fn insert_nonblocking(self: &Arc<Self>, uri: String, content: Bytes, meta: Meta) {
+        if self.on_disk() {
+            let cachestore = Arc::clone(self);
+            tokio::task::spawn_blocking(move || cachestore.insert(uri, content, meta));
+        } else {
+            self.insert(uri, content, meta);
+        }
+    }
+
+    /// mark an object as fresh again, after upstream confirmed
+    /// it has not changed
+    pub async fn renew(&self, uri: &str) {
+        let now = SystemTime::now();
+        if let Some(entry) = self.store.lock().touch(uri) {
+            entry.fetched = now;
+        } else {
+            return;
+        }
+
+        // objects on disk remember when they were fetched
+        // through their modification time
+        let Some(ref dir) = self.dir else {
+            return;
+        };
+        let path = dir.join(disk::filename(uri));
+        let res = tokio::task::spawn_blocking(move || {
+            fs::File::options()
+                .write(true)
+                .open(path)
+                .and_then(|f| f.set_modified(now))
+        })
+        .await
+        .unwrap_or_else(|e| Err(io::Error::other(e)));
+        if let Err(_e) = res {
+            #[cfg(feature = "log")]
+            eprintln!("failed to renew {} on disk: {:?}", uri, _e);
+        }
+    }
+
+    /// remove an object from the cache, returning how many bytes
+    /// were freed
+    pub fn remove(&self, uri: &str) -> Option<usize> {
+        let size = self.store.lock().remove(uri)?;
+        self.unlink(vec![uri.to_string()]);
+
+        #[cfg(feature = "log")]
+        eprintln!("removed {} freeing {} B", uri, size);
+
+        Some(size)
+    }
+
+    /// remove every object with a uri matching pattern, where a
+    /// `*` matches anything. returns how many objects and bytes
+    /// were freed
+    pub fn purge(&self, pattern: &str) -> (usize, usize) {
+        let prefix = pattern.split('*').next().unwrap_or_default();
+        let mut store = self.store.lock();
+        let matched: Vec<String> = store
+            .entries
+            .range(prefix.to_string()..)
+            .take_while(|(uri, _)| uri.starts_with(prefix))
+            .filter(|(uri, _)| glob(pattern, uri))
+            .map(|(uri, _)| uri.clone())
+            .collect();
+
+        let mut freed = 0;
+        for uri in &matched {
+            freed += store.remove(uri).unwrap_or_default();
+
+            #[cfg(feature = "log")]
+            eprintln!("removed {}", uri);
+        }
+        drop(store);
+
+        let count = matched.len();
+        self.unlink(matched);
+        (count, freed)
+    }
+
+    /// rebuild the index from objects already in the cache
+    /// directory, returning the uris of everything found
+    ///
+    /// leftover temporary files and unreadable objects are removed
+    pub fn load(&self) -> io::Result<Vec<String>> {
+        let Some(ref dir) = self.dir else {
+            return Ok(Vec::new());
+        };
+
+        let mut found = Vec::new();
+        for dirent in fs::read_dir(dir)? {
+            let dirent = dirent?;
+            let path = dirent.path();
+            let name = dirent.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+
+            if name.starts_with(".tmp") {
+                _ = fs::remove_file(&path);
+                continue;
+            }
+            let Some(uri) = disk::unescape(name) else {
+                continue;
+            };
+
+            let res = (|| {
+                let file = fs::File::open(&path)?;
+                let stat = file.metadata()?;
+                let header = disk::read_header(BufReader::new(file))?;
+                if stat.len() != (header.start + header.size) as u64 {
+                    return Err(disk::invalid("truncated object"));
+                }
+                Ok((stat.modified()?, header))
+            })();
+
+            match res {
+                Ok((modified, header)) => found.push((modified, uri, header)),
+                Err(_e) => {
+                    #[cfg(feature = "log")]
+                    eprintln!("removing unreadable {}: {:?}", uri, _e);
+
+                    _ = fs::remove_file(&path);
+                }
+            }
+        }
+
+        // oldest first, so the most recently written objects are
+        // the last to be evicted
+        found.sort_by_key(|(modified, _, _)| *modified);
+
+        let mut store = self.store.lock();
+        for (fetched, uri, header) in found {
+            let inserted = header.inserted.unwrap_or(fetched);
+            let etag = header
+                .etag
+                .unwrap_or_else(|| conditional::generate_etag(inserted, header.size));
+
+            store.remove(&uri);
+            store.insert(
+                uri,
+                Entry {
+                    content: None,
+                    meta: header.meta,
+                    etag,
+                    size: header.size,
+                    inserted,
+                    fetched,
+                    hits: 0,
+                    last_used: 0,
+                },
+            );
+        }
+        let evicted = self.shrink(&mut store, 0);
+
+        #[cfg(feature = "log")]
+        eprintln!(
+            "loaded {} objects using {} B",
+            store.entries.len(),
+            store.size
+        );
+
+        let uris = store.entries.keys().cloned().collect();
+        drop(store);
+        self.unlink(evicted);
+        Ok(uris)
+    }
+
+    /// list everything cached with a uri starting with prefix
+    pub fn list(&self, prefix: &str) -> Vec<Listing> {
+        self.store
+            .lock()
+            .entries
+            .range(prefix.to_string()..)
+            .take_while(|(uri, _)| uri.starts_with(prefix))
+            .map(|(uri, entry)| Listing {
+                uri: uri.clone(),
+                size: entry.size,
+                inserted: entry.inserted,
+                mirror: entry.meta.mirror,
+                hits: entry.hits,
+            })
+            .collect()
+    }
+
+    /// the object that would be evicted first to make room for
+    /// another of size bytes, if any would need to be
+    pub fn victim(&self, size: usize) -> Option<String> {
+        let max = self.max_size?;
+        let store = self.store.lock();
+        if store.size + size <= max {
+            return None;
+        }
+        store.recency.first_key_value().map(|(_, uri)| uri.clone())
+    }
+
+    /// total size of all cached objects in bytes
+    pub fn size(&self) -> usize {
+        self.store.lock().size
+    }
+
+    /// evict objects until there is room for another of size
+    /// bytes, returning the uris of those to unlink
+    fn shrink(&self, store: &mut Store, size: usize) -> Vec<String> {
+        let mut evicted = Vec::new();
+        let Some(max) = self.max_size else {
+            return evicted;
+        };
+        while store.size + size > max {
+            let Some(uri) = store.evict() else {
+                break;
+            };
+            evicted.push(uri);
+            self.metrics.trace_evict();
+        }
+        evicted
+    }
+
+    /// delete the files of objects no longer in the cache, on a
+    /// thread that is allowed to block if there is a runtime. must
+    /// not be called with the store locked
+    fn unlink(&self, uris: Vec<String>) {
+        let Some(ref dir) = self.dir else {
+            return;
+        };
+        if uris.is_empty() {
+            return;
+        }
+
+        let (dir, store) = (dir.clone(), Arc::clone(&self.store));
+        let unlink = move || {
+            for uri in uris {
+                // moved aside with the lock held, so a copy that
+                // was inserted again meanwhile is never the one
+                // deleted
+                let tmp = disk::tmp_path(&dir);
+                let res = {
+                    let store = store.lock();
+                    if store.entries.contains_key(&uri) {
+                        continue;
+                    }
+                    fs::rename(dir.join(disk::filename(&uri)), &tmp)
+                };
+                if let Err(_e) = res.and_then(|()| fs::remove_file(&tmp)) {
+                    #[cfg(feature = "log")]
+                    eprintln!("failed to remove {} from disk: {:?}", uri, _e);
+                }
+            }
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(unlink)),
+            Err(_) => unlink(),
+        }
+    }
+}
+
+/// match s against a pattern where `*` matches any run of characters
+fn glob(pattern: &str, s: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = s.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts: Vec<&str> = parts.collect();
+    let Some(last) = parts.pop() else {
+        // no wildcards at all
+        return rest.is_empty();
+    };
+    for part in parts {
+        let Some(i) = rest.find(part) else {
+            return false;
+        };
+        rest = &rest[i + part.len()..];
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+pub struct FanoutBody<T: Body + Unpin> {
+    body: T,
+    uri: String,
+    buffer: Vec<u8>,
+    meta: Meta,
+    cachestore: Arc<CacheStore>,
+    /// what upstream promised through Content-Length, if anything
+    length: Option<u64>,
+    /// set once the buffer has been dealt with, one way or another
+    finished: bool,
+    /// what to check the buffer with before caching it, and
+    /// which mirror it came from
+    verifier: Option<(Arc<Verifier>, usize)>,
+}
+
+impl<T: Body + Unpin> FanoutBody<T> {
+    pub fn new(body: T, uri: String, meta: Meta, cachestore: Arc<CacheStore>) -> Self {
+        // hyper derives an exact size hint from Content-Length
+        let length = body.size_hint().exact();
+
+        Self {
+            body,
+            uri,
+            buffer: Vec::new(),
+            meta,
+            cachestore,
+            length,
+            finished: false,
+            verifier: None,
+        }
+    }
+
+    /// check the body against its md5 sidecar before caching it,
+    /// with mindex being the mirror it came from
+    pub fn verify(mut self, verifier: Arc<Verifier>, mindex: usize) -> Self {
+        self.verifier = Some((verifier, mindex));
+        self
+    }
+
+    /// throw away whatever was received, it cannot be trusted
+    fn abort(mut self: Pin<&mut Self>) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+        self.buffer = Vec::new();
+
+        #[cfg(feature = "log")]
+        eprintln!("not caching {} since upstream failed", self.uri);
+
+        self.cachestore.metrics.trace_abort();
+    }
+
+    fn done(mut self: Pin<&mut Self>) {
+        if self.finished {
+            return;
+        }
+        if self
+            .length
+            .is_some_and(|len| len != self.buffer.len() as u64)
+        {
+            return self.abort();
+        }
+        self.finished = true;
+
+        if self.buffer.is_empty() {
+            return;
+        }
+
+        let uri = self.uri.clone();
+        let cachestore = Arc::clone(&self.cachestore);
+
+        // we cannot take the buffer since self is pinned,
+        // consume it instead
+        let content = std::mem::take(&mut self.buffer);
+        let meta = self.meta.clone();
+
+        match self.verifier.take() {
+            Some((verifier, mindex)) => {
+                tokio::task::spawn(verifier.admit(uri, content.into(), meta, mindex));
+            }
+            None => cachestore.insert_nonblocking(uri, content.into(), meta),
+        }
+    }
+}
+
+impl<T: Body<Data = Bytes, Error = Error> + Unpin> Body for FanoutBody<T> {
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let res = Pin::new(&mut self.body).poll_frame(cx);
+        match res {
+            Poll::Ready(Some(Ok(ref frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    self.buffer.append(&mut data.to_vec());
+                }
+                if self.is_end_stream() {
+                    self.done();
+                }
+            }
+            Poll::Ready(Some(Err(_))) => self.abort(),
+            Poll::Ready(None) => self.done(),
+            Poll::Pending => (),
+        };
+
+        res
+    }
+
+    #[inline]
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    #[inline]
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::*;
+    use http_body_util::{BodyExt, Full};
+    use hyper::body::Body;
+
+    #[tokio::test]
+    async fn cache_static() {
+        let inp =
+            Full::new(Bytes::from_static(b"you wouldn't download a fox")).map_err(|e| match e {});
+        let cachestore = CacheStore::new(None, None, None, Metrics::new());
+        let body = FanoutBody::new(
+            inp,
+            "/test".to_string(),
+            Meta::default(),
+            Arc::clone(&cachestore),
+        );
+
+        // FIXME: replace with std::task::Waker::noop once stable
+        // https://github.com/rust-lang/rust/issues/98286
+        let waker = futures::task::noop_waker_ref();
+        let mut cx = std::task::Context::from_waker(waker);
+        let mut pinned = std::pin::pin!(body);
+
+        assert_eq!(pinned.size_hint().exact(), Some(27));
+
+        let pf = pinned.as_mut().poll_frame(&mut cx);
+        let read = match pf {
+            Poll::Ready(Some(Ok(ref frame))) => frame.data_ref().unwrap(),
+            e => panic!("failed to poll frame: {e:?}"),
+        };
+        assert_eq!(read, &Bytes::from_static(b"you wouldn't download a fox"));
+
+        assert_eq!(pinned.size_hint().exact(), Some(0));
+
+        // wait for FanoutBody to finish caching in the background
+        tokio::task::yield_now().await;
+        let res = cachestore.get("/test").await.unwrap().content;
+        assert_eq!(res, Bytes::from_static(b"you wouldn't download a fox"));
+
+        match pinned.as_mut().poll_frame(&mut cx) {
+            Poll::Ready(None) => (),
+            e => panic!("failed to poll frame: {e:?}"),
+        };
+
+        // make sure extra polling does not mess up the cache
+        tokio::task::yield_now().await;
+        let res = cachestore.get("/test").await.unwrap().content;
+        assert_eq!(res, Bytes::from_static(b"you wouldn't download a fox"));
+
+        let res = cachestore.remove("/test").unwrap();
+        assert_eq!(res, 27);
+    }
+
+    /// pretends to be longer than it is, like a connection
+    /// that dropped halfway through
+    struct Truncated<T>(T);
+
+    impl<T: Body + Unpin> Body for Truncated<T> {
+        type Data = T::Data;
+        type Error = T::Error;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+            Pin::new(&mut self.0).poll_frame(cx)
+        }
+
+        fn size_hint(&self) -> SizeHint {
+            SizeHint::with_exact(9001)
+        }
+    }
+
+    #[tokio::test]
+    async fn cache_truncated() {
+        let inp = Full::new(Bytes::from_static(b"you wouldn't download half a fox"))
+            .map_err(|e| match e {});
+        let metrics = Metrics::new();
+        let cachestore = CacheStore::new(None, None, None, Arc::clone(&metrics));
+        let body = FanoutBody::new(
+            Truncated(inp),
+            "/test".to_string(),
+            Meta::default(),
+            Arc::clone(&cachestore),
+        );
+
+        body.collect().await.unwrap();
+        assert!(cachestore.get("/test").await.is_none());
+        assert!(metrics.output().contains("aborted 1\n"));
+    }
+
+    #[tokio::test]
+    async fn evict_lru() {
+        let metrics = Metrics::new();
+        let cachestore = CacheStore::new(None, Some(10), None, Arc::clone(&metrics));
+
+        cachestore.insert(
+            "/a".to_string(),
+            Bytes::from_static(b"yip"),
+            Meta::default(),
+        );
+        cachestore.insert(
+            "/b".to_string(),
+            Bytes::from_static(b"yap"),
+            Meta::default(),
+        );
+        cachestore.insert(
+            "/c".to_string(),
+            Bytes::from_static(b"yop"),
+            Meta::default(),
+        );
+
+        // make /a the most recently used, leaving /b to be evicted
+        assert!(cachestore.get("/a").await.is_some());
+        cachestore.insert(
+            "/d".to_string(),
+            Bytes::from_static(b"awoo"),
+            Meta::default(),
+        );
+
+        assert!(cachestore.get("/b").await.is_none());
+        assert!(cachestore.get("/a").await.is_some());
+        assert!(cachestore.get("/c").await.is_some());
+        assert!(cachestore.get("/d").await.is_some());
+        assert_eq!(cachestore.size(), 10);
+
+        // too big to ever fit, should not evict anything
+        cachestore.insert(
+            "/e".to_string(),
+            Bytes::from_static(b"screeeeeeeee"),
+            Meta::default(),
+        );
+        assert!(cachestore.get("/e").await.is_none());
+        assert_eq!(cachestore.size(), 10);
+
+        assert!(metrics.output().contains("evictions 1\n"));
+    }
+
+    /// wait a bit for a file to be unlinked
+    async fn gone(path: &std::path::Path) -> bool {
+        for _ in 0..100 {
+            if !path.exists() {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn cache_disk() {
+        let dir = std::env::temp_dir().join(format!("tcrelay-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let cachestore = CacheStore::new(Some(dir.clone()), Some(10), None, Metrics::new());
+        cachestore.insert(
+            "/a".to_string(),
+            Bytes::from_static(b"yip"),
+            Meta::default(),
+        );
+        cachestore.insert(
+            "/b".to_string(),
+            Bytes::from_static(b"yap"),
+            Meta::default(),
+        );
+        assert!(dir.join("%2Fa").exists());
+
+        assert_eq!(
+            cachestore.get("/a").await.unwrap().content,
+            Bytes::from_static(b"yip")
+        );
+
+        // evicting should clean up the file too
+        cachestore.insert(
+            "/c".to_string(),
+            Bytes::from_static(b"awoooo"),
+            Meta::default(),
+        );
+        assert!(gone(&dir.join("%2Fb")).await);
+        assert_eq!(
+            cachestore.get("/c").await.unwrap().content,
+            Bytes::from_static(b"awoooo")
+        );
+
+        assert_eq!(cachestore.remove("/a"), Some(3));
+        assert!(gone(&dir.join("%2Fa")).await);
+
+        // a mangled file should be treated as a miss
+        fs::write(dir.join("%2Fc"), b"tcrelay 1\nsize: 6\n\nawo").unwrap();
+        assert!(cachestore.get("/c").await.is_none());
+        assert_eq!(cachestore.size(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn load_disk() {
+        let dir = std::env::temp_dir().join(format!("tcrelay-load-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let cachestore = CacheStore::new(Some(dir.clone()), None, None, Metrics::new());
+        cachestore.insert(
+            "/a".to_string(),
+            Bytes::from_static(b"yip"),
+            Meta::default(),
+        );
+        cachestore.insert(
+            "/b".to_string(),
+            Bytes::from_static(b"yap"),
+            Meta::default(),
+        );
+        let etag = cachestore.get("/b").await.unwrap().validators.etag;
+        fs::write(dir.join(".tmp1-2"), b"half written").unwrap();
+        fs::write(dir.join("%2Fc"), b"tcrelay 1\nsize: 9\n\nawo").unwrap();
+
+        let cachestore = CacheStore::new(Some(dir.clone()), None, None, Metrics::new());
+        let mut found = cachestore.load().unwrap();
+        found.sort();
+        assert_eq!(found, ["/a", "/b"]);
+        assert_eq!(cachestore.size(), 6);
+        let object = cachestore.get("/b").await.unwrap();
+        assert_eq!(object.content, Bytes::from_static(b"yap"));
+        // clients should not notice a restart
+        assert_eq!(object.validators.etag, etag);
+
+        assert!(!dir.join(".tmp1-2").exists());
+        assert!(!dir.join("%2Fc").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stale() {
+        let meta = Meta {
+            etag: Some("\"fox\"".to_string()),
+            ..Default::default()
+        };
+        let conditions = meta.conditions();
+        assert_eq!(conditions.get("If-None-Match").unwrap(), "\"fox\"");
+        assert!(conditions.get("If-Modified-Since").is_none());
+
+        let cachestore = CacheStore::new(None, None, Some(Duration::ZERO), Metrics::new());
+        cachestore.insert("/a".to_string(), Bytes::from_static(b"yip"), meta.clone());
+        let res = cachestore.get("/a").await.unwrap();
+        assert!(res.stale);
+        assert_eq!(res.meta, meta);
+
+        let cachestore = CacheStore::new(None, None, Some(Duration::from_secs(60)), Metrics::new());
+        cachestore.insert("/a".to_string(), Bytes::from_static(b"yip"), meta);
+        assert!(!cachestore.get("/a").await.unwrap().stale);
+        cachestore.renew("/a").await;
+        assert!(!cachestore.get("/a").await.unwrap().stale);
+    }
+
+    #[test]
+    fn head() {
+        let cachestore = CacheStore::new(None, None, None, Metrics::new());
+        assert!(cachestore.head("/a").is_none());
+
+        let meta = Meta {
+            etag: Some("\"fox\"".to_string()),
+            ..Default::default()
+        };
+        cachestore.insert("/a".to_string(), Bytes::from_static(b"yip"), meta);
+        let head = cachestore.head("/a").unwrap();
+        assert_eq!(head.size, 3);
+        assert_eq!(head.validators.etag, "\"fox\"");
+        assert!(!head.stale);
+        assert_eq!(cachestore.list("/a")[0].hits, 0);
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob("/15.x/*", "/15.x/x86/tcz/sed.tcz"));
+        assert!(glob("/15.x/*/tcz/*.tcz", "/15.x/x86/tcz/sed.tcz"));
+        assert!(!glob("/15.x/*/tcz/*.tcz", "/15.x/x86/tcz/sed.tcz.dep"));
+        assert!(glob("/*.tcz*", "/sed.tcz.md5.txt"));
+        assert!(glob("/a*a", "/aa"));
+        assert!(!glob("/a*a", "/a"));
+        assert!(glob("/exact", "/exact"));
+        assert!(!glob("/exact", "/exactly"));
+    }
+
+    #[test]
+    fn purge() {
+        let cachestore = CacheStore::new(None, None, None, Metrics::new());
+        for uri in [
+            "/14.x/x86/tcz/a.tcz",
+            "/15.x/x86/tcz/b.tcz",
+            "/15.x/x86/tcz/b.tcz.dep",
+            "/15.x/x86_64/tcz/c.tcz",
+        ] {
+            cachestore.insert(uri.to_string(), Bytes::from_static(b"yip"), Meta::default());
+        }
+
+        assert_eq!(cachestore.purge("/15.x/*.tcz"), (2, 6));
+        assert_eq!(cachestore.purge("/15.x/*.tcz"), (0, 0));
+        assert_eq!(cachestore.purge("/15.x/x86/*"), (1, 3));
+        assert_eq!(cachestore.size(), 3);
+    }
+}
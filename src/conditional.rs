@@ -0,0 +1,184 @@
+use hyper::{
+    header::{self, HeaderMap, HeaderValue},
+    http::response::Builder,
+};
+use std::time::{Duration, SystemTime};
+
+/// what clients can use to check if their copy of an object is
+/// still the same as ours
+#[derive(Clone, Debug, PartialEq)]
+pub struct Validators {
+    /// always a strong entity tag, including the quotes
+    pub etag: String,
+    pub last_modified: SystemTime,
+}
+
+/// make up a strong entity tag for content that came without one
+pub fn generate_etag(inserted: SystemTime, size: usize) -> String {
+    let nanos = inserted
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!("\"{nanos:x}-{size:x}\"")
+}
+
+fn is_weak(etag: &str) -> bool {
+    etag.starts_with("W/")
+}
+
+/// dates in headers only have second precision
+fn truncate(time: SystemTime) -> SystemTime {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+fn parse_date(value: &HeaderValue) -> Option<SystemTime> {
+    httpdate::parse_http_date(value.to_str().ok()?).ok()
+}
+
+impl Validators {
+    /// keep upstream validators where they are usable, otherwise
+    /// fall back to ones derived from when the object was inserted
+    pub fn new(
+        etag: Option<&str>,
+        last_modified: Option<&str>,
+        inserted: SystemTime,
+        size: usize,
+    ) -> Self {
+        let etag = etag
+            .filter(|etag| !is_weak(etag))
+            .map(str::to_string)
+            .unwrap_or_else(|| generate_etag(inserted, size));
+        let last_modified = last_modified
+            .and_then(|date| httpdate::parse_http_date(date).ok())
+            .unwrap_or(inserted);
+
+        Self {
+            etag,
+            last_modified,
+        }
+    }
+
+    /// add the validators to a response
+    pub fn headers(&self, res: Builder) -> Builder {
+        res.header(header::ETAG, &self.etag).header(
+            header::LAST_MODIFIED,
+            httpdate::fmt_http_date(self.last_modified),
+        )
+    }
+
+    /// if the client already has this object according to its
+    /// If-None-Match or If-Modified-Since, so a 304 will do
+    pub fn not_modified(&self, headers: &HeaderMap) -> bool {
+        // If-Modified-Since is only looked at without
+        // If-None-Match, since entity tags are more precise
+        if headers.contains_key(header::IF_NONE_MATCH) {
+            let ours = self.etag.trim_start_matches("W/");
+            return headers
+                .get_all(header::IF_NONE_MATCH)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(','))
+                .map(str::trim)
+                .any(|theirs| theirs == "*" || theirs.trim_start_matches("W/") == ours);
+        }
+
+        headers
+            .get(header::IF_MODIFIED_SINCE)
+            .and_then(parse_date)
+            .is_some_and(|since| truncate(self.last_modified) <= since)
+    }
+
+    /// if a Range should be honored given the If-Range sent with
+    /// it, which needs to match exactly
+    pub fn if_range(&self, value: &HeaderValue) -> bool {
+        let Ok(value) = value.to_str() else {
+            return false;
+        };
+        if value.starts_with('"') {
+            return value == self.etag;
+        }
+        if is_weak(value) {
+            return false;
+        }
+
+        httpdate::parse_http_date(value).is_ok_and(|date| date == truncate(self.last_modified))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conditional::*;
+
+    fn validators() -> Validators {
+        Validators::new(
+            Some("\"fox\""),
+            Some("Tue, 15 Nov 1994 08:12:31 GMT"),
+            SystemTime::now(),
+            3,
+        )
+    }
+
+    fn headers(name: &'static str, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn fallbacks() {
+        let inserted = SystemTime::UNIX_EPOCH + Duration::from_secs(60);
+        let v = Validators::new(Some("W/\"fox\""), Some("yesterday"), inserted, 3);
+        assert_eq!(v.etag, "\"df8475800-3\"");
+        assert_eq!(v.last_modified, inserted);
+
+        let v = Validators::new(None, None, inserted, 4);
+        assert_eq!(v.etag, "\"df8475800-4\"");
+    }
+
+    #[test]
+    fn not_modified() {
+        let v = validators();
+        assert!(v.not_modified(&headers("If-None-Match", "\"fox\"")));
+        assert!(v.not_modified(&headers("If-None-Match", "\"wolf\", W/\"fox\"")));
+        assert!(v.not_modified(&headers("If-None-Match", "*")));
+        assert!(!v.not_modified(&headers("If-None-Match", "\"wolf\"")));
+
+        assert!(v.not_modified(&headers(
+            "If-Modified-Since",
+            "Tue, 15 Nov 1994 08:12:31 GMT"
+        )));
+        assert!(v.not_modified(&headers(
+            "If-Modified-Since",
+            "Wed, 16 Nov 1994 08:12:31 GMT"
+        )));
+        assert!(!v.not_modified(&headers(
+            "If-Modified-Since",
+            "Mon, 14 Nov 1994 08:12:31 GMT"
+        )));
+        assert!(!v.not_modified(&headers("If-Modified-Since", "whenever")));
+        assert!(!v.not_modified(&HeaderMap::new()));
+
+        // If-None-Match wins
+        let mut both = headers("If-None-Match", "\"wolf\"");
+        both.insert(
+            "If-Modified-Since",
+            HeaderValue::from_static("Wed, 16 Nov 1994 08:12:31 GMT"),
+        );
+        assert!(!v.not_modified(&both));
+    }
+
+    #[test]
+    fn if_range() {
+        let v = validators();
+        assert!(v.if_range(&HeaderValue::from_static("\"fox\"")));
+        assert!(v.if_range(&HeaderValue::from_static("Tue, 15 Nov 1994 08:12:31 GMT")));
+
+        assert!(!v.if_range(&HeaderValue::from_static("W/\"fox\"")));
+        assert!(!v.if_range(&HeaderValue::from_static("\"wolf\"")));
+        assert!(!v.if_range(&HeaderValue::from_static("Wed, 16 Nov 1994 08:12:31 GMT")));
+    }
+}
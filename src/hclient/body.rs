@@ -0,0 +1,222 @@
+use super::{timeout::Timed, Client, Error};
+use hyper::{
+    body::{Bytes, Frame, SizeHint},
+    header::{self, HeaderMap, HeaderName, HeaderValue},
+    Method, Response, StatusCode,
+};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+use tokio::task::JoinHandle;
+
+/// where to pick a body back up from if its mirror fails partway
+pub struct Resume {
+    client: Arc<Client>,
+    /// left to try, in order
+    mirrors: Vec<String>,
+    path: String,
+    received: u64,
+    length: u64,
+    /// ETag or Last-Modified, for If-Range and to check against
+    /// what the next mirror has, so a different version is never
+    /// stitched on
+    validator: (HeaderName, HeaderValue),
+}
+
+impl Resume {
+    /// only full bodies of a known length with a strong ETag or
+    /// a Last-Modified can be resumed, since the pieces have to be
+    /// checked to line up
+    pub fn new(
+        client: Arc<Client>,
+        res: &Response<Body>,
+        mirrors: Vec<String>,
+        path: &str,
+    ) -> Option<Self> {
+        if res.status() != StatusCode::OK {
+            return None;
+        }
+        let length = hyper::body::Body::size_hint(res.body()).exact()?;
+
+        let headers = res.headers();
+        let validator = match headers.get(header::ETAG) {
+            Some(etag) if !etag.as_bytes().starts_with(b"W/") => (header::ETAG, etag.clone()),
+            _ => (
+                header::LAST_MODIFIED,
+                headers.get(header::LAST_MODIFIED)?.clone(),
+            ),
+        };
+
+        Some(Self {
+            client,
+            mirrors,
+            path: path.to_string(),
+            received: 0,
+            length,
+            validator,
+        })
+    }
+
+    /// ask the remaining mirrors for the rest of the body, giving
+    /// back what it was found with and the mirrors left after it
+    fn fetch(&mut self) -> impl Future<Output = (Option<Timed>, Vec<String>)> + Send + 'static {
+        let client = Arc::clone(&self.client);
+        let mut mirrors = std::mem::take(&mut self.mirrors);
+        let path = self.path.clone();
+        let (from, length) = (self.received, self.length);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::RANGE,
+            HeaderValue::from_str(&format!("bytes={from}-")).expect("range is ascii"),
+        );
+        headers.insert(header::IF_RANGE, self.validator.1.clone());
+        let validator = self.validator.clone();
+
+        async move {
+            while !mirrors.is_empty() {
+                let m = mirrors.remove(0);
+                let url = format!("{m}{path}");
+                let Ok(res) = client.follow(&Method::GET, &m, url, &headers).await else {
+                    continue;
+                };
+                if !lines_up(&res, from, length, &validator) {
+                    #[cfg(feature = "log")]
+                    eprintln!("{} cannot continue {} from byte {}", m, path, from);
+                    continue;
+                }
+
+                #[cfg(feature = "log")]
+                eprintln!("resumed {} from byte {} with {}", path, from, m);
+                client.metrics.trace_resume();
+                return (Some(res.into_body().inner), mirrors);
+            }
+            (None, mirrors)
+        }
+    }
+}
+
+/// if res is exactly the rest of the same version of a body of
+/// length, from from on. mirrors that ignore If-Range would give
+/// back whatever they have
+fn lines_up(
+    res: &Response<Body>,
+    from: u64,
+    length: u64,
+    (name, value): &(HeaderName, HeaderValue),
+) -> bool {
+    let expected = format!("bytes {from}-{}/{length}", length.saturating_sub(1));
+    res.status() == StatusCode::PARTIAL_CONTENT
+        && res
+            .headers()
+            .get(header::CONTENT_RANGE)
+            .is_some_and(|range| range.as_bytes() == expected.as_bytes())
+        && res.headers().get(name) == Some(value)
+}
+
+/// looking for the rest of a body, called off if the body is
+/// dropped before it is found
+struct Pending(JoinHandle<(Option<Timed>, Vec<String>)>);
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// a response body from a mirror, which carries on from another
+/// mirror if the first one fails partway through, so whoever
+/// reads it sees one continuous body
+pub struct Body {
+    inner: Timed,
+    resume: Option<Resume>,
+    pending: Option<Pending>,
+    /// what failed, to give up with if nobody has the rest
+    error: Option<Error>,
+}
+
+impl Body {
+    pub fn resume(mut self, resume: Option<Resume>) -> Self {
+        self.resume = resume;
+        self
+    }
+}
+
+impl From<Timed> for Body {
+    fn from(inner: Timed) -> Self {
+        Self {
+            inner,
+            resume: None,
+            pending: None,
+            error: None,
+        }
+    }
+}
+
+impl hyper::body::Body for Body {
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        loop {
+            if let Some(pending) = &mut self.pending {
+                let found = ready!(Pin::new(&mut pending.0).poll(cx));
+                self.pending = None;
+                match found {
+                    Ok((Some(inner), mirrors)) => {
+                        self.inner = inner;
+                        self.error = None;
+                        if let Some(resume) = &mut self.resume {
+                            resume.mirrors = mirrors;
+                        }
+                    }
+                    _ => {
+                        self.resume = None;
+                        return Poll::Ready(self.error.take().map(Err));
+                    }
+                }
+            }
+
+            match ready!(Pin::new(&mut self.inner).poll_frame(cx)) {
+                Some(Ok(frame)) => {
+                    if let (Some(resume), Some(data)) = (&mut self.resume, frame.data_ref()) {
+                        resume.received += data.len() as u64;
+                    }
+                    return Poll::Ready(Some(Ok(frame)));
+                }
+                Some(Err(e)) => {
+                    let Some(resume) = self.resume.as_mut().filter(|r| !r.mirrors.is_empty())
+                    else {
+                        return Poll::Ready(Some(Err(e)));
+                    };
+
+                    #[cfg(feature = "log")]
+                    eprintln!("lost {} at byte {}: {:?}", resume.path, resume.received, e);
+
+                    let fetch = resume.fetch();
+                    self.pending = Some(Pending(tokio::task::spawn(fetch)));
+                    self.error = Some(e);
+                }
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+
+    #[inline]
+    fn is_end_stream(&self) -> bool {
+        self.pending.is_none() && self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match &self.resume {
+            Some(resume) => SizeHint::with_exact(resume.length.saturating_sub(resume.received)),
+            None => self.inner.size_hint(),
+        }
+    }
+}
@@ -1,125 +1,1146 @@
+use crate::metrics::Metrics;
 use http_body_util::Empty;
-use hyper::{body::Bytes, Request, Response, Uri};
-use hyper_util::rt::TokioIo;
-use std::sync::Arc;
+use hyper::{
+    body::Bytes,
+    client::conn::{http1::SendRequest, http2},
+    HeaderMap, Method, Request, Response, StatusCode, Uri,
+};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use pool::Pool;
+use std::{future::Future, sync::Arc, time::Duration};
+use timeout::{timed_out, within, Timed};
 use tokio::{io, net::TcpStream};
-use tokio_rustls::{rustls::pki_types, TlsConnector};
+use tokio_rustls::{
+    rustls::{pki_types, ClientConfig},
+    TlsConnector,
+};
 
+mod body;
+mod pool;
+mod timeout;
 mod tls_configs;
 
+pub use body::Body;
+use body::Resume;
+pub use timeout::{MirrorTimeouts, Timeouts};
+
 pub enum Scheme {
     Https,
     HttpsInsecure,
     Http,
 }
 
-pub async fn try_get(
-    mirrors: &[String],
-    path: &str,
-) -> Option<(Response<hyper::body::Incoming>, usize)> {
-    for (i, m) in mirrors.iter().enumerate() {
-        let url = format!("{m}{path}");
-        let uri = match url.parse() {
-            Ok(u) => u,
-            Err(_e) => {
+/// which http versions to speak with mirrors
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum Version {
+    /// http2 with mirrors that offer it over tls, http/1.1
+    /// otherwise
+    Auto,
+    /// always http/1.1
+    Http1,
+    /// always http2, without tls too. mirrors that cannot do it
+    /// are skipped
+    Http2,
+}
+
+impl Version {
+    fn alpn(self) -> &'static [&'static [u8]] {
+        match self {
+            Version::Auto => &[b"h2", b"http/1.1"],
+            Version::Http1 => &[b"http/1.1"],
+            Version::Http2 => &[b"h2"],
+        }
+    }
+}
+
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// statuses that point at where to find the object instead
+const REDIRECTS: [StatusCode; 5] = [
+    StatusCode::MOVED_PERMANENTLY,
+    StatusCode::FOUND,
+    StatusCode::SEE_OTHER,
+    StatusCode::TEMPORARY_REDIRECT,
+    StatusCode::PERMANENT_REDIRECT,
+];
+
+/// where a Location header sent in response to a request for
+/// base points to, as in RFC 3986 section 5.2
+fn resolve(base: &Uri, location: &str) -> Option<String> {
+    let scheme = base.scheme_str()?;
+    // the fragment is only for whoever ends up reading it
+    let location = location.split('#').next()?;
+    let (path, query) = match location.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (location, None),
+    };
+
+    if let Some(rest) = path.strip_prefix("//") {
+        let (authority, path) = rest.find('/').map_or((rest, ""), |i| rest.split_at(i));
+        return Some(join(scheme, authority, &remove_dots(path), query));
+    }
+    if path.contains("://") {
+        let uri: Uri = location.parse().ok()?;
+        // upgrading to https is fine, but a mirror does not get to
+        // turn off certificate checks or tls itself
+        let allowed = match uri.scheme_str()? {
+            "https" => true,
+            "http" => scheme == "http",
+            _ => false,
+        };
+        return (allowed && uri.authority().is_some()).then(|| location.to_string());
+    }
+
+    let authority = base.authority()?.as_str();
+    let path = match path {
+        "" => {
+            let query = query.or_else(|| base.query());
+            return Some(join(scheme, authority, base.path(), query));
+        }
+        _ if path.starts_with('/') => remove_dots(path),
+        // relative to the directory of the path that was asked for
+        _ => {
+            let dir = base.path().rsplit_once('/').map_or("", |(dir, _)| dir);
+            remove_dots(&format!("{dir}/{path}"))
+        }
+    };
+    Some(join(scheme, authority, &path, query))
+}
+
+fn join(scheme: &str, authority: &str, path: &str, query: Option<&str>) -> String {
+    match query {
+        Some(query) => format!("{scheme}://{authority}{path}?{query}"),
+        None => format!("{scheme}://{authority}{path}"),
+    }
+}
+
+/// resolve the . and .. segments in an absolute path
+fn remove_dots(path: &str) -> String {
+    let mut segments = Vec::new();
+    for segment in path.split('/').skip(1) {
+        match segment {
+            "." => (),
+            ".." => _ = segments.pop(),
+            _ => segments.push(segment),
+        }
+    }
+    // a path ending in one still points at a directory
+    if path.ends_with("/.") || path.ends_with("/..") {
+        segments.push("");
+    }
+    format!("/{}", segments.join("/"))
+}
+
+/// why a mirror did not come through with an object
+enum Miss {
+    /// it answered, just not with the object
+    Answered,
+    /// it failed to answer, so might do better on another try
+    Failed,
+}
+
+enum Conn {
+    Http1(SendRequest<Empty<Bytes>>),
+    Http2(http2::SendRequest<Empty<Bytes>>),
+}
+
+/// how to talk to mirrors
+pub struct Options {
+    /// idle connections to keep to each mirror, 0 to not keep any
+    pub max_idle: usize,
+    /// how long an idle connection is kept
+    pub idle_timeout: Duration,
+    pub version: Version,
+    /// redirects to follow from a mirror before giving up on it
+    pub max_redirects: usize,
+    pub timeouts: Timeouts,
+    /// for mirrors that need more or less patience
+    pub mirror_timeouts: Vec<MirrorTimeouts>,
+    /// times to ask the mirrors that failed again, unless
+    /// another one said it does not have the object
+    pub retries: u32,
+    /// wait before the first retry, doubling for each one after
+    pub backoff: Duration,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            max_idle: 8,
+            idle_timeout: Duration::from_secs(60),
+            version: Version::Auto,
+            max_redirects: 5,
+            timeouts: Timeouts::default(),
+            mirror_timeouts: Vec::new(),
+            retries: 1,
+            backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+/// makes requests to mirrors, keeping connections around to reuse
+/// for later requests
+pub struct Client {
+    pool: Arc<Pool>,
+    version: Version,
+    max_redirects: usize,
+    timeouts: Timeouts,
+    mirror_timeouts: Vec<(String, Timeouts)>,
+    retries: u32,
+    backoff: Duration,
+    tls: Arc<ClientConfig>,
+    tls_insecure: Arc<ClientConfig>,
+    metrics: Arc<Metrics>,
+}
+
+impl Client {
+    pub fn new(options: Options, metrics: Arc<Metrics>) -> Arc<Self> {
+        let version = options.version;
+        let mirror_timeouts = options
+            .mirror_timeouts
+            .iter()
+            .map(|t| (t.mirror.clone(), t.apply(options.timeouts)))
+            .collect();
+
+        Arc::new(Self {
+            pool: Arc::new(Pool::new(options.max_idle, options.idle_timeout)),
+            version,
+            max_redirects: options.max_redirects,
+            timeouts: options.timeouts,
+            mirror_timeouts,
+            retries: options.retries,
+            backoff: options.backoff,
+            tls: tls_configs::with_alpn(&tls_configs::CONF, version.alpn()),
+            tls_insecure: tls_configs::with_alpn(&tls_configs::CONF_INSECURE, version.alpn()),
+            metrics,
+        })
+    }
+
+    /// the mirror url is from and the timeouts to use for it. urls
+    /// from unknown mirrors are reported by their origin
+    fn timeouts<'a>(&'a self, url: &'a str) -> (&'a str, Timeouts) {
+        let boundary = |mirror: &str| {
+            url.strip_prefix(mirror).is_some_and(|rest| {
+                rest.is_empty() || mirror.ends_with('/') || rest.starts_with(['/', '?'])
+            })
+        };
+        if let Some((mirror, timeouts)) = self.mirror_timeouts.iter().find(|(m, _)| boundary(m)) {
+            return (mirror, *timeouts);
+        }
+
+        let origin = url.find("://").map_or(url, |i| {
+            let rest = &url[i + 3..];
+            let end = rest.find(['/', '?']).map_or(url.len(), |j| i + 3 + j);
+            &url[..end]
+        });
+        (origin, self.timeouts)
+    }
+
+    /// close connections that have been idle too long
+    pub fn prune(&self) {
+        self.pool.prune();
+    }
+
+    /// number of idle http/1.1 connections kept around
+    pub fn idle(&self) -> usize {
+        self.pool.idle()
+    }
+
+    /// number of http2 connections kept around, idle or not
+    pub fn shared(&self) -> usize {
+        self.pool.shared()
+    }
+
+    /// get path from the first mirror that has it, sending along
+    /// extra headers with each request
+    ///
+    /// if the mirror fails partway through the body, the rest is
+    /// fetched from the others
+    pub async fn try_get(
+        self: &Arc<Self>,
+        mirrors: &[String],
+        path: &str,
+        headers: &HeaderMap,
+    ) -> Option<(Response<Body>, usize)> {
+        self.try_request(&Method::GET, mirrors, path, headers).await
+    }
+
+    /// like try_get, but with any method
+    ///
+    /// a 304 or 416 is returned as is, so conditional and range
+    /// requests can be answered by any mirror
+    pub async fn try_request(
+        self: &Arc<Self>,
+        method: &Method,
+        mirrors: &[String],
+        path: &str,
+        headers: &HeaderMap,
+    ) -> Option<(Response<Body>, usize)> {
+        // mirrors to ask, by index
+        let mut left: Vec<usize> = (0..mirrors.len()).collect();
+        for attempt in 0..=self.retries {
+            if attempt > 0 {
+                let backoff = self.backoff.saturating_mul(1 << (attempt - 1).min(16));
                 #[cfg(feature = "log")]
-                eprintln!("failed to parse {}: {:?}", url, _e);
-                continue;
+                eprintln!("trying mirrors for {} again in {:?}", path, backoff);
+                tokio::time::sleep(backoff).await;
             }
-        };
-        match get_request(uri).await {
-            Ok(r) => {
-                if !r.status().is_success() {
+
+            let mut failed = Vec::new();
+            let mut answered = false;
+            for i in left {
+                let m = &mirrors[i];
+                match self.follow(method, m, format!("{m}{path}"), headers).await {
+                    Ok(r) => {
+                        if method != Method::GET {
+                            return Some((r, i));
+                        }
+                        // the next mirrors first, then around to
+                        // this one again
+                        let others = mirrors[i + 1..].iter().chain(&mirrors[..=i]);
+                        let resume =
+                            Resume::new(Arc::clone(self), &r, others.cloned().collect(), path);
+                        return Some((r.map(|body| body.resume(resume)), i));
+                    }
+                    Err(Miss::Failed) => failed.push(i),
+                    Err(Miss::Answered) => answered = true,
+                }
+            }
+            // a mirror saying it does not have it is as good an
+            // answer as any, and the rest were all asked already
+            if answered || failed.is_empty() {
+                break;
+            }
+            left = failed;
+        }
+        None
+    }
+
+    /// request url from a mirror, following where it redirects
+    /// to for up to max_redirects hops
+    async fn follow(
+        &self,
+        method: &Method,
+        mirror: &str,
+        mut url: String,
+        headers: &HeaderMap,
+    ) -> Result<Response<Body>, Miss> {
+        let (_, timeouts) = self.timeouts(&url);
+        let mut visited = Vec::new();
+        loop {
+            let uri: Uri = match url.parse() {
+                Ok(u) => u,
+                Err(_e) => {
+                    #[cfg(feature = "log")]
+                    eprintln!("failed to parse {}: {:?}", url, _e);
+                    return Err(Miss::Answered);
+                }
+            };
+            let r = match self
+                .send(method, uri.clone(), headers, mirror, timeouts)
+                .await
+            {
+                Ok(r) => r,
+                Err(_e) => {
+                    #[cfg(feature = "log")]
+                    eprintln!("failed to get {}: {:?}", url, _e);
+                    return Err(Miss::Failed);
+                }
+            };
+
+            if REDIRECTS.contains(&r.status()) {
+                let Some(next) = r
+                    .headers()
+                    .get(hyper::header::LOCATION)
+                    .and_then(|l| l.to_str().ok())
+                    .and_then(|l| resolve(&uri, l))
+                else {
+                    #[cfg(feature = "log")]
+                    eprintln!(
+                        "{} from {} without a usable location",
+                        r.status().as_str(),
+                        url
+                    );
+                    return Err(Miss::Answered);
+                };
+                if visited.len() >= self.max_redirects {
+                    #[cfg(feature = "log")]
+                    eprintln!("too many redirects from {}", url);
+                    return Err(Miss::Answered);
+                }
+                if next == url || visited.contains(&next) {
                     #[cfg(feature = "log")]
-                    eprintln!("{} from {}", r.status().as_str(), url);
-                    continue;
+                    eprintln!("redirect loop from {} to {}", url, next);
+                    return Err(Miss::Answered);
                 }
 
                 #[cfg(feature = "log")]
-                eprintln!("got {}", url);
-                return Some((r, i));
+                eprintln!("{} redirected to {}", url, next);
+                self.metrics.trace_redirect(mirror);
+                visited.push(std::mem::replace(&mut url, next));
+                continue;
             }
-            Err(_e) => {
+
+            let passed = [StatusCode::NOT_MODIFIED, StatusCode::RANGE_NOT_SATISFIABLE];
+            if !r.status().is_success() && !passed.contains(&r.status()) {
                 #[cfg(feature = "log")]
-                eprintln!("failed to get {}: {:?}", url, _e);
-                continue;
+                eprintln!("{} from {}", r.status().as_str(), url);
+                return Err(match r.status().is_server_error() {
+                    true => Miss::Failed,
+                    false => Miss::Answered,
+                });
             }
-        };
+
+            #[cfg(feature = "log")]
+            eprintln!("got {}", url);
+            return Ok(r);
+        }
     }
-    None
-}
 
-pub async fn get_request(
-    uri: Uri,
-) -> Result<Response<hyper::body::Incoming>, Box<dyn std::error::Error + Send + Sync>> {
-    let scheme = match uri.scheme_str() {
-        Some("https") => Scheme::Https,
-        Some("https+insecure") => Scheme::HttpsInsecure,
-        Some("http") | None => Scheme::Http,
-        Some(_) => return Err("unsupported scheme".into()),
-    };
+    pub async fn get_request(
+        &self,
+        uri: Uri,
+        headers: &HeaderMap,
+    ) -> Result<Response<Body>, Error> {
+        self.request(&Method::GET, uri, headers).await
+    }
 
-    let h = uri.host().ok_or("mangled host")?;
-    let p = uri.port_u16().unwrap_or(match scheme {
-        Scheme::Https | Scheme::HttpsInsecure => 443,
-        Scheme::Http => 80,
-    });
-    let addr = format!("{h}:{p}");
+    pub async fn request(
+        &self,
+        method: &Method,
+        uri: Uri,
+        headers: &HeaderMap,
+    ) -> Result<Response<Body>, Error> {
+        let url = uri.to_string();
+        let (mirror, timeouts) = self.timeouts(&url);
+        self.send(method, uri, headers, mirror, timeouts).await
+    }
+
+    /// make a single request, with mirror being what to report
+    /// timeouts as
+    async fn send(
+        &self,
+        method: &Method,
+        uri: Uri,
+        headers: &HeaderMap,
+        mirror: &str,
+        timeouts: Timeouts,
+    ) -> Result<Response<Body>, Error> {
+        let scheme = match uri.scheme_str() {
+            Some("https") => Scheme::Https,
+            Some("https+insecure") => Scheme::HttpsInsecure,
+            Some("http") | None => Scheme::Http,
+            Some(_) => return Err("unsupported scheme".into()),
+        };
+
+        let h = uri.host().ok_or("mangled host")?;
+        let p = uri.port_u16().unwrap_or(match scheme {
+            Scheme::Https | Scheme::HttpsInsecure => 443,
+            Scheme::Http => 80,
+        });
+        let addr = format!("{h}:{p}");
+        // connections are only shared with the same scheme, so
+        // an insecure one is never used for a secure mirror
+        let key = format!("{}://{addr}", uri.scheme_str().unwrap_or("http"));
+
+        let authority = uri.authority().ok_or("mangled authority")?.as_str();
+        // keep the query, which redirects to a cdn tend to need
+        let path = uri.path_and_query().map_or("/", |p| p.as_str());
+        // http2 takes the host from the uri rather than a Host
+        // header, and has no idea what https+insecure is
+        let http2_uri: Uri = format!(
+            "{}://{authority}{}",
+            match scheme {
+                Scheme::Http => "http",
+                Scheme::Https | Scheme::HttpsInsecure => "https",
+            },
+            path
+        )
+        .parse()?;
+        let build = |http2: bool| {
+            let req = Request::builder().method(method);
+            let req = if http2 {
+                req.uri(&http2_uri)
+            } else {
+                req.uri(path).header(hyper::header::HOST, authority)
+            };
+            let mut req = req.body(Empty::<Bytes>::new())?;
+            req.headers_mut().extend(headers.clone());
+            Ok::<_, Error>(req)
+        };
+
+        let first_byte = |res: Option<hyper::Result<Response<hyper::body::Incoming>>>| match res {
+            Some(res) => res.map_err(Error::from).map(|res| {
+                res.map(|body| {
+                    Body::from(Timed::new(
+                        body,
+                        timeouts.body,
+                        mirror.to_string(),
+                        Arc::clone(&self.metrics),
+                    ))
+                })
+            }),
+            None => Err(timed_out(&self.metrics, mirror, "first-byte")),
+        };
+
+        // only one http2 connection to a mirror is wanted, so
+        // wait on anyone already opening one that might be
+        let may_share = match scheme {
+            Scheme::Http => self.version == Version::Http2,
+            Scheme::Https | Scheme::HttpsInsecure => self.version != Version::Http1,
+        };
+        let mut opening = None;
+        let shared = match self.pool.share(&key) {
+            None if may_share && self.pool.enabled() => match self.pool.open(&key).await {
+                Some(o) => {
+                    opening = Some(o);
+                    // in case it was opened just before
+                    self.pool.share(&key)
+                }
+                None => self.pool.share(&key),
+            },
+            shared => shared,
+        };
+        if let Some(mut sender) = shared {
+            drop(opening.take());
+            match within(timeouts.first_byte, sender.try_send_request(build(true)?)).await {
+                Some(Err(_)) => (),
+                res => {
+                    self.metrics.trace_conn_reuse();
+                    return first_byte(res.map(|res| res.map_err(|e| e.into_error())));
+                }
+            }
+        }
+        if let Some(mut sender) = self.pool.take(&key) {
+            // the mirror probably closed it while it sat idle if
+            // this fails, so have another go with a fresh one
+            match within(timeouts.first_byte, sender.try_send_request(build(false)?)).await {
+                Some(Err(_)) => (),
+                res => {
+                    self.metrics.trace_conn_reuse();
+                    // a mirror that timed out is not worth keeping
+                    // a connection to
+                    if res.is_some() {
+                        self.release(key, sender);
+                    }
+                    return first_byte(res.map(|res| res.map_err(|e| e.into_error())));
+                }
+            }
+        }
+
+        let conn = within(timeouts.connect, self.connect(&scheme, h, &addr))
+            .await
+            .ok_or_else(|| timed_out(&self.metrics, mirror, "connect"))??;
+        self.metrics.trace_conn_open();
+
+        match conn {
+            Conn::Http1(mut sender) => {
+                drop(opening);
+                let res = within(timeouts.first_byte, sender.send_request(build(false)?)).await;
+                if res.is_some() {
+                    self.release(key, sender);
+                }
+                first_byte(res)
+            }
+            Conn::Http2(mut sender) => {
+                self.pool.put_shared(key, sender.clone());
+                drop(opening);
+                first_byte(within(timeouts.first_byte, sender.send_request(build(true)?)).await)
+            }
+        }
+    }
 
-    let stream = TcpStream::connect(addr).await?;
+    /// open a connection to addr, over tls if the scheme wants it
+    async fn connect(&self, scheme: &Scheme, host: &str, addr: &str) -> Result<Conn, Error> {
+        let stream = TcpStream::connect(addr).await?;
+        Ok(match scheme {
+            Scheme::Https => self.connect_tls(&self.tls, host, stream).await?,
+            Scheme::HttpsInsecure => self.connect_tls(&self.tls_insecure, host, stream).await?,
+            // without tls there is nothing to negotiate with, so
+            // http2 is only spoken when forced
+            Scheme::Http if self.version == Version::Http2 => {
+                Conn::Http2(handshake2(stream).await?)
+            }
+            Scheme::Http => Conn::Http1(handshake(stream).await?),
+        })
+    }
 
-    match scheme {
-        Scheme::Https => {
-            let connector = TlsConnector::from(Arc::clone(&*tls_configs::CONF));
-            let domain = pki_types::ServerName::try_from(h)?.to_owned();
-            let stream = connector.connect(domain, stream).await?;
+    /// speak whichever version the mirror picked over alpn
+    async fn connect_tls(
+        &self,
+        config: &Arc<ClientConfig>,
+        host: &str,
+        stream: TcpStream,
+    ) -> Result<Conn, Error> {
+        let connector = TlsConnector::from(Arc::clone(config));
+        let domain = pki_types::ServerName::try_from(host)?.to_owned();
+        let stream = connector.connect(domain, stream).await?;
 
-            get_with_stream(stream, uri).await
+        if stream.get_ref().1.alpn_protocol() == Some(b"h2") {
+            Ok(Conn::Http2(handshake2(stream).await?))
+        } else if self.version == Version::Http2 {
+            Err("mirror did not agree to http2".into())
+        } else {
+            Ok(Conn::Http1(handshake(stream).await?))
         }
-        Scheme::HttpsInsecure => {
-            let connector = TlsConnector::from(Arc::clone(&*tls_configs::CONF_INSECURE));
-            let domain = pki_types::ServerName::try_from(h)?.to_owned();
-            let stream = connector.connect(domain, stream).await?;
+    }
 
-            get_with_stream(stream, uri).await
+    /// put a connection back in the pool once the response body
+    /// has been read, if it is still usable by then
+    fn release(&self, key: String, mut sender: SendRequest<Empty<Bytes>>) {
+        if !self.pool.enabled() {
+            return;
         }
-        Scheme::Http => get_with_stream(stream, uri).await,
+        let pool = Arc::clone(&self.pool);
+        tokio::task::spawn(async move {
+            if sender.ready().await.is_ok() {
+                pool.put(key, sender);
+            }
+        });
     }
 }
 
-async fn get_with_stream<T: io::AsyncRead + io::AsyncWrite + Send + Unpin + 'static>(
+async fn handshake<T: io::AsyncRead + io::AsyncWrite + Send + Unpin + 'static>(
     stream: T,
-    uri: Uri,
-) -> Result<Response<hyper::body::Incoming>, Box<dyn std::error::Error + Send + Sync>> {
+) -> Result<SendRequest<Empty<Bytes>>, Error> {
     let io = TokioIo::new(stream);
-    let (mut sender, conn) = hyper::client::conn::http1::handshake(io).await?;
+    let (sender, conn) = hyper::client::conn::http1::handshake(io).await?;
+    drive(conn);
+    Ok(sender)
+}
+
+async fn handshake2<T: io::AsyncRead + io::AsyncWrite + Send + Unpin + 'static>(
+    stream: T,
+) -> Result<http2::SendRequest<Empty<Bytes>>, Error> {
+    let io = TokioIo::new(stream);
+    let (sender, conn) = http2::handshake(TokioExecutor::new(), io).await?;
+    drive(conn);
+    Ok(sender)
+}
 
+/// run a connection in the background until it closes
+fn drive(conn: impl Future<Output = Result<(), hyper::Error>> + Send + 'static) {
     tokio::task::spawn(async move {
         if let Err(_e) = conn.await {
             #[cfg(feature = "log")]
             eprintln!("connection failed: {:?}", _e);
         }
     });
+}
 
-    let addr = uri.authority().expect("failed to parse authority").as_str();
-
-    let req = Request::builder()
-        .uri(uri.path())
-        .header(hyper::header::HOST, addr)
-        .body(Empty::<Bytes>::new())?;
+/// a server on localhost for tests to point clients at, answering
+/// every request with handler. returns its base url
+#[cfg(test)]
+pub(crate) async fn test_server<F, R, B, E>(version: hyper::Version, handler: F) -> String
+where
+    F: Fn(Request<hyper::body::Incoming>) -> R + Clone + Send + 'static,
+    R: Future<Output = Result<Response<B>, E>> + Send + 'static,
+    B: hyper::body::Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<Error>,
+    E: Into<Error>,
+{
+    use hyper::server::conn::{http1, http2};
 
-    Ok(sender.send_request(req).await?)
+    let listen = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listen.local_addr().unwrap());
+    tokio::task::spawn(async move {
+        loop {
+            let (stream, _) = listen.accept().await.unwrap();
+            let io = TokioIo::new(stream);
+            let service = hyper::service::service_fn(handler.clone());
+            if version == hyper::Version::HTTP_2 {
+                let conn = http2::Builder::new(TokioExecutor::new()).serve_connection(io, service);
+                tokio::task::spawn(conn);
+            } else {
+                tokio::task::spawn(http1::Builder::new().serve_connection(io, service));
+            }
+        }
+    });
+    url
 }
 
 #[cfg(test)]
 mod tests {
     use crate::hclient::*;
+    use futures::StreamExt;
+    use http_body_util::{BodyExt, Full, StreamBody};
+    use hyper::body::Frame;
+    use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+    use tokio::net::TcpListener;
+
+    fn client() -> Arc<Client> {
+        Client::new(Options::default(), Metrics::new())
+    }
+
+    #[tokio::test]
+    async fn reuse() {
+        let mirror = test_server(hyper::Version::HTTP_11, |_| async {
+            Ok::<_, hyper::Error>(Response::new(Full::new(Bytes::from_static(b"yip"))))
+        })
+        .await;
+        let url = format!("{mirror}/");
+
+        let metrics = Metrics::new();
+        let client = Client::new(Options::default(), Arc::clone(&metrics));
+        for _ in 0..3 {
+            let res = client
+                .get_request(url.parse().unwrap(), &HeaderMap::new())
+                .await
+                .unwrap();
+            let body = res.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(body, Bytes::from_static(b"yip"));
+            // give the connection a moment to go back in the pool
+            for _ in 0..100 {
+                if client.idle() == 1 {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        }
+
+        let output = metrics.output();
+        assert!(output.contains("connections_opened 1\n"));
+        assert!(output.contains("connections_reused 2\n"));
+        assert_eq!(client.idle(), 1);
+
+        let unpooled = Client::new(
+            Options {
+                max_idle: 0,
+                ..Default::default()
+            },
+            Metrics::new(),
+        );
+        unpooled
+            .get_request(url.parse().unwrap(), &HeaderMap::new())
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(unpooled.idle(), 0);
+    }
+
+    #[tokio::test]
+    async fn multiplex() {
+        let mirror = test_server(hyper::Version::HTTP_2, |_| async {
+            Ok::<_, hyper::Error>(Response::new(Full::new(Bytes::from_static(b"yap"))))
+        })
+        .await;
+        let url = format!("{mirror}/");
+
+        let metrics = Metrics::new();
+        let client = Client::new(
+            Options {
+                version: Version::Http2,
+                ..Default::default()
+            },
+            Arc::clone(&metrics),
+        );
+        let get = || async {
+            let res = client
+                .get_request(url.parse().unwrap(), &HeaderMap::new())
+                .await
+                .unwrap();
+            assert_eq!(res.version(), hyper::Version::HTTP_2);
+            res.into_body().collect().await.unwrap().to_bytes()
+        };
+        // all at once from the start, so they have to wait for
+        // the first to connect
+        let (a, b, c) = tokio::join!(get(), get(), get());
+        assert_eq!(
+            [a, b, c],
+            [
+                Bytes::from_static(b"yap"),
+                Bytes::from_static(b"yap"),
+                Bytes::from_static(b"yap")
+            ]
+        );
+
+        assert_eq!(get().await, Bytes::from_static(b"yap"));
+
+        let output = metrics.output();
+        assert!(output.contains("connections_opened 1\n"));
+        assert!(output.contains("connections_reused 3\n"));
+        assert_eq!((client.idle(), client.shared()), (0, 1));
+
+        // an http/1.1 client cannot talk to it
+        let http1 = Client::new(
+            Options {
+                version: Version::Http1,
+                ..Default::default()
+            },
+            Metrics::new(),
+        );
+        assert!(http1
+            .get_request(url.parse().unwrap(), &HeaderMap::new())
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn resolve_location() {
+        let insecure: Uri = "http://mirror.example/x".parse().unwrap();
+        assert_eq!(
+            resolve(&insecure, "https://mirror.example/x").as_deref(),
+            Some("https://mirror.example/x")
+        );
+        assert_eq!(
+            resolve(&insecure, "http://cdn.example/x").as_deref(),
+            Some("http://cdn.example/x")
+        );
+
+        let base: Uri = "https+insecure://mirror.example/tcz/fox.tcz"
+            .parse()
+            .unwrap();
+        let cases = [
+            ("https://cdn.example/x", Some("https://cdn.example/x")),
+            ("//cdn.example/x", Some("https+insecure://cdn.example/x")),
+            ("/x?sig=1", Some("https+insecure://mirror.example/x?sig=1")),
+            ("x", Some("https+insecure://mirror.example/tcz/x")),
+            (
+                "tcz/wolf",
+                Some("https+insecure://mirror.example/tcz/tcz/wolf"),
+            ),
+            ("../x", Some("https+insecure://mirror.example/x")),
+            ("../../x/./y", Some("https+insecure://mirror.example/x/y")),
+            ("..", Some("https+insecure://mirror.example/")),
+            (
+                "fox.tcz?sig=a",
+                Some("https+insecure://mirror.example/tcz/fox.tcz?sig=a"),
+            ),
+            (
+                "?sig=a",
+                Some("https+insecure://mirror.example/tcz/fox.tcz?sig=a"),
+            ),
+            ("x#top", Some("https+insecure://mirror.example/tcz/x")),
+            ("//cdn.example", Some("https+insecure://cdn.example/")),
+            ("http://cdn.example/x", None),
+            ("https+insecure://cdn.example/x", None),
+            ("ftp://cdn.example/x", None),
+            ("http://", None),
+        ];
+        for (location, expected) in cases {
+            assert_eq!(resolve(&base, location).as_deref(), expected, "{location}");
+        }
+    }
+
+    #[tokio::test]
+    async fn redirects() {
+        let mirror = test_server(hyper::Version::HTTP_11, |req| async move {
+            let location = match req.uri().path() {
+                "/fox" => "/tcz/fox",
+                "/tcz/fox" => "wolf",
+                "/loop" => "/loop2",
+                "/loop2" => "/loop",
+                "/far" => "/far1",
+                "/far1" => "/far2",
+                _ => {
+                    return Ok::<_, hyper::Error>(Response::new(Full::new(Bytes::copy_from_slice(
+                        req.uri().path().as_bytes(),
+                    ))))
+                }
+            };
+            Ok(Response::builder()
+                .status(StatusCode::FOUND)
+                .header(hyper::header::LOCATION, location)
+                .body(Full::new(Bytes::new()))
+                .unwrap())
+        })
+        .await;
+
+        let metrics = Metrics::new();
+        let client = Client::new(
+            Options {
+                max_redirects: 1,
+                ..Default::default()
+            },
+            Arc::clone(&metrics),
+        );
+        let mirrors = [mirror.clone()];
+        assert!(client
+            .try_get(&mirrors, "/loop", &HeaderMap::new())
+            .await
+            .is_none());
+        assert!(client
+            .try_get(&mirrors, "/far", &HeaderMap::new())
+            .await
+            .is_none());
+
+        let client = Client::new(Options::default(), Arc::clone(&metrics));
+        let (res, _) = client
+            .try_get(&mirrors, "/fox", &HeaderMap::new())
+            .await
+            .unwrap();
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, Bytes::from_static(b"/tcz/wolf"));
+        assert!(client
+            .try_get(&mirrors, "/loop", &HeaderMap::new())
+            .await
+            .is_none());
+
+        // 1 each for /loop and /far with the first client, then 2
+        // for /fox and 1 for /loop before it came back around
+        assert!(metrics
+            .output()
+            .contains(&format!("redirects{{mirror=\"{mirror}\"}} 5\n")));
+    }
+
+    #[tokio::test]
+    async fn timeouts() {
+        let mirror = test_server(hyper::Version::HTTP_11, |req| async move {
+            if req.uri().path() == "/stall" {
+                std::future::pending::<()>().await;
+            }
+            let chunks = futures::stream::iter([Ok::<_, hyper::Error>(Frame::data(
+                Bytes::from_static(b"yip"),
+            ))])
+            .chain(futures::stream::pending());
+            Ok::<_, hyper::Error>(Response::new(StreamBody::new(chunks)))
+        })
+        .await;
+
+        let metrics = Metrics::new();
+        let timeouts = Timeouts {
+            first_byte: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let client = Client::new(
+            Options {
+                mirror_timeouts: vec![format!("{mirror}=body:0").parse().unwrap()],
+                timeouts,
+                retries: 0,
+                ..Default::default()
+            },
+            Arc::clone(&metrics),
+        );
+        let mirrors = [mirror.clone()];
+        assert!(client
+            .try_get(&mirrors, "/stall", &HeaderMap::new())
+            .await
+            .is_none());
+
+        let client = Client::new(
+            Options {
+                timeouts: Timeouts {
+                    body: Duration::from_millis(50),
+                    ..timeouts
+                },
+                ..Default::default()
+            },
+            Arc::clone(&metrics),
+        );
+        let (res, _) = client
+            .try_get(&mirrors, "/trickle", &HeaderMap::new())
+            .await
+            .unwrap();
+        let mut body = res.into_body();
+        let frame = body.frame().await.unwrap().unwrap();
+        assert_eq!(frame.into_data().unwrap(), Bytes::from_static(b"yip"));
+        assert!(body.frame().await.unwrap().is_err());
+
+        let output = metrics.output();
+        assert!(output.contains(&format!(
+            "timeouts{{mirror=\"{mirror}\",stage=\"first-byte\"}} 1\n"
+        )));
+        assert!(output.contains(&format!(
+            "timeouts{{mirror=\"{mirror}\",stage=\"body\"}} 1\n"
+        )));
+    }
+
+    #[test]
+    fn timeout_labels() {
+        let client = Client::new(
+            Options {
+                mirror_timeouts: vec!["http://a.example=body:5".parse().unwrap()],
+                ..Default::default()
+            },
+            Metrics::new(),
+        );
+        let body = |url| {
+            let (mirror, timeouts) = client.timeouts(url);
+            (mirror, timeouts.body.as_secs())
+        };
+        assert_eq!(body("http://a.example/fox.tcz"), ("http://a.example", 5));
+        assert_eq!(body("http://a.example"), ("http://a.example", 5));
+        assert_eq!(
+            body("http://a.example.evil/fox.tcz"),
+            ("http://a.example.evil", 30)
+        );
+        assert_eq!(
+            body("http://b.example:8080/tcz/fox.tcz?sig=a"),
+            ("http://b.example:8080", 30)
+        );
+    }
+
+    #[tokio::test]
+    async fn slow_reader() {
+        let mirror = test_server(hyper::Version::HTTP_11, |_| async {
+            let chunks = futures::stream::iter([Bytes::from_static(b"yip")])
+                .chain(futures::stream::once(async {
+                    tokio::time::sleep(Duration::from_millis(300)).await;
+                    Bytes::from_static(b"yap")
+                }))
+                .map(|data| Ok::<_, hyper::Error>(Frame::data(data)));
+            Ok::<_, hyper::Error>(Response::new(StreamBody::new(chunks)))
+        })
+        .await;
+
+        let metrics = Metrics::new();
+        let client = Client::new(
+            Options {
+                timeouts: Timeouts {
+                    body: Duration::from_millis(100),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            Arc::clone(&metrics),
+        );
+        let (res, _) = client
+            .try_get(&[mirror], "/fox", &HeaderMap::new())
+            .await
+            .unwrap();
+        let mut body = res.into_body();
+        body.frame().await.unwrap().unwrap();
+        // longer than the timeout, but the mirror has only been
+        // quiet for a bit of it once asked for more
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        let frame = body.frame().await.unwrap().unwrap();
+        assert_eq!(frame.into_data().unwrap(), Bytes::from_static(b"yap"));
+        assert!(!metrics.output().contains("timeouts{"));
+    }
+
+    #[tokio::test]
+    async fn retries() {
+        let asked = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&asked);
+        let mirror = test_server(hyper::Version::HTTP_11, move |req| {
+            // flaky the first time, and never has /gone
+            let first = counter.fetch_add(1, Relaxed) == 0;
+            async move {
+                let status = match req.uri().path() {
+                    "/gone" => StatusCode::NOT_FOUND,
+                    _ if first => StatusCode::SERVICE_UNAVAILABLE,
+                    _ => StatusCode::OK,
+                };
+                Response::builder()
+                    .status(status)
+                    .body(Full::new(Bytes::from_static(b"yip")))
+            }
+        })
+        .await;
+
+        let client = Client::new(
+            Options {
+                backoff: Duration::from_millis(1),
+                ..Default::default()
+            },
+            Metrics::new(),
+        );
+        let mirrors = [mirror];
+        assert!(client
+            .try_get(&mirrors, "/fox", &HeaderMap::new())
+            .await
+            .is_some());
+        assert_eq!(asked.load(Relaxed), 2);
+
+        assert!(client
+            .try_get(&mirrors, "/gone", &HeaderMap::new())
+            .await
+            .is_none());
+        assert_eq!(asked.load(Relaxed), 3);
+
+        // a mirror that is down is not worth waiting on when
+        // another one already said it does not have it
+        let listen = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let down = format!("http://{}", listen.local_addr().unwrap());
+        drop(listen);
+        let client = Client::new(
+            Options {
+                backoff: Duration::from_secs(10),
+                ..Default::default()
+            },
+            Metrics::new(),
+        );
+        let mirrors = [down, mirrors[0].clone()];
+        let headers = HeaderMap::new();
+        let gone = client.try_get(&mirrors, "/gone", &headers);
+        let gone = tokio::time::timeout(Duration::from_secs(1), gone).await;
+        assert!(gone.unwrap().is_none());
+        assert_eq!(asked.load(Relaxed), 4);
+    }
+
+    #[tokio::test]
+    async fn resume() {
+        // the first mirror gives up halfway through, the second
+        // has the rest and the third has a different version
+        let broken = test_server(hyper::Version::HTTP_11, |req| async move {
+            let chunks = futures::stream::iter([Ok(Frame::data(Bytes::from_static(b"yip")))])
+                .chain(futures::stream::once(async {
+                    // after the head has gone out
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    Err(io::Error::other("oops"))
+                }));
+            let res = Response::builder().header("content-length", "6");
+            // nothing to tell versions apart by
+            let res = match req.uri().path() {
+                "/plain" => res,
+                _ => res.header("etag", "\"fox\""),
+            };
+            res.body(StreamBody::new(chunks))
+        })
+        .await;
+        let mut mirrors = vec![broken];
+        for etag in ["\"fox\"", "\"wolf\""] {
+            let mirror = test_server(hyper::Version::HTTP_11, move |req| async move {
+                let headers = req.headers();
+                assert_eq!(headers.get("if-range").unwrap(), "\"fox\"");
+                assert_eq!(headers.get("range").unwrap(), "bytes=3-");
+                // without checking If-Range
+                Response::builder()
+                    .status(StatusCode::PARTIAL_CONTENT)
+                    .header("content-range", "bytes 3-5/6")
+                    .header("etag", etag)
+                    .body(Full::new(Bytes::from_static(b"yap")))
+            })
+            .await;
+            mirrors.push(mirror);
+        }
+
+        let metrics = Metrics::new();
+        let client = Client::new(Options::default(), Arc::clone(&metrics));
+        let (res, mindex) = client
+            .try_get(&mirrors[..2], "/fox", &HeaderMap::new())
+            .await
+            .unwrap();
+        assert_eq!(mindex, 0);
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, Bytes::from_static(b"yipyap"));
+        assert!(metrics.output().contains("resumed 1\n"));
+
+        // the broken mirror cannot help itself, the other one has
+        // the wrong thing, and without an etag there is no telling
+        for (mirrors, path) in [
+            (&mirrors[..1], "/fox"),
+            (&[mirrors[0].clone(), mirrors[2].clone()][..], "/fox"),
+            (&mirrors[..2], "/plain"),
+        ] {
+            let (res, _) = client
+                .try_get(mirrors, path, &HeaderMap::new())
+                .await
+                .unwrap();
+            assert!(res.into_body().collect().await.is_err(), "{mirrors:?}");
+        }
+        assert!(metrics.output().contains("resumed 1\n"));
+    }
 
     #[tokio::test]
     #[ignore]
     async fn get() {
         let url = "http://tinycorelinux.net/10.x/x86/tcz/mirrors.tcz.md5.txt";
-        let res = get_request(url.parse().unwrap()).await.unwrap();
+        let res = client()
+            .get_request(url.parse().unwrap(), &HeaderMap::new())
+            .await
+            .unwrap();
         assert!(res.status().is_success());
     }
 
@@ -127,17 +1148,26 @@ mod tests {
     #[ignore]
     async fn get_https() {
         let url = "https://mozilla-modern.badssl.com/";
-        let res = get_request(url.parse().unwrap()).await.unwrap();
+        let res = client()
+            .get_request(url.parse().unwrap(), &HeaderMap::new())
+            .await
+            .unwrap();
         assert!(res.status().is_success());
 
         let url = "https+insecure://self-signed.badssl.com/";
-        let res = get_request(url.parse().unwrap()).await.unwrap();
+        let res = client()
+            .get_request(url.parse().unwrap(), &HeaderMap::new())
+            .await
+            .unwrap();
         assert!(res.status().is_success());
 
         let url = "https://mitm-software.badssl.com:443/";
         // would be better to check the error kind specifically for
         // InvalidCertificate(UnknownIssuer), but it is boxed :(
-        get_request(url.parse().unwrap()).await.unwrap_err();
+        assert!(client()
+            .get_request(url.parse().unwrap(), &HeaderMap::new())
+            .await
+            .is_err());
     }
 
     #[tokio::test]
@@ -150,7 +1180,8 @@ mod tests {
             "http://tinycorelinux.net", // should actually get
         ]
         .map(|m| m.to_string());
-        let res = try_get(&mirrors, "/10.x/x86/tcz/sed.tcz.md5.txt")
+        let res = client()
+            .try_get(&mirrors, "/10.x/x86/tcz/sed.tcz.md5.txt", &HeaderMap::new())
             .await
             .unwrap();
         assert_eq!(res.0.headers().get("content-length").unwrap(), "42");
@@ -0,0 +1,193 @@
+use http_body_util::Empty;
+use hyper::{
+    body::Bytes,
+    client::conn::{http1::SendRequest, http2},
+};
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::OwnedMutexGuard;
+
+struct Idle {
+    sender: SendRequest<Empty<Bytes>>,
+    since: Instant,
+}
+
+/// an http2 connection, which can carry many requests at once
+struct Shared {
+    sender: http2::SendRequest<Empty<Bytes>>,
+    since: Instant,
+}
+
+/// idle connections to each mirror, ready for another request
+pub struct Pool {
+    /// keyed by scheme and address, most recently used last
+    idle: Mutex<HashMap<String, Vec<Idle>>>,
+    /// at most one per mirror, used by every request to it
+    shared: Mutex<HashMap<String, Shared>>,
+    /// mirrors a connection that might be shared is being opened
+    /// to, held until it is
+    opening: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+    max_idle: usize,
+    timeout: Duration,
+}
+
+impl Pool {
+    pub fn new(max_idle: usize, timeout: Duration) -> Self {
+        Self {
+            idle: Mutex::default(),
+            shared: Mutex::default(),
+            opening: Mutex::default(),
+            max_idle,
+            timeout,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.max_idle != 0 && !self.timeout.is_zero()
+    }
+
+    fn usable(&self, conn: &Idle) -> bool {
+        conn.since.elapsed() < self.timeout && !conn.sender.is_closed()
+    }
+
+    /// the most recently used connection that is still usable
+    pub fn take(&self, key: &str) -> Option<SendRequest<Empty<Bytes>>> {
+        let mut idle = self.idle.lock();
+        let conns = idle.get_mut(key)?;
+        conns.retain(|conn| self.usable(conn));
+
+        let found = conns.pop().map(|conn| conn.sender);
+        if conns.is_empty() {
+            idle.remove(key);
+        }
+        found
+    }
+
+    pub fn put(&self, key: String, sender: SendRequest<Empty<Bytes>>) {
+        if !self.enabled() {
+            return;
+        }
+
+        let mut idle = self.idle.lock();
+        let conns = idle.entry(key).or_default();
+        conns.retain(|conn| self.usable(conn));
+        if conns.len() >= self.max_idle {
+            conns.remove(0);
+        }
+        conns.push(Idle {
+            sender,
+            since: Instant::now(),
+        });
+    }
+
+    fn shareable(&self, conn: &Shared) -> bool {
+        conn.since.elapsed() < self.timeout && !conn.sender.is_closed()
+    }
+
+    /// a handle to the http2 connection to a mirror, if there is
+    /// one that is still usable
+    pub fn share(&self, key: &str) -> Option<http2::SendRequest<Empty<Bytes>>> {
+        let mut shared = self.shared.lock();
+        let conn = shared.get_mut(key)?;
+        if !self.shareable(conn) {
+            shared.remove(key);
+            return None;
+        }
+
+        conn.since = Instant::now();
+        Some(conn.sender.clone())
+    }
+
+    /// make an http2 connection available to later requests,
+    /// replacing any other one to the same mirror
+    pub fn put_shared(&self, key: String, sender: http2::SendRequest<Empty<Bytes>>) {
+        if !self.enabled() {
+            return;
+        }
+
+        self.shared.lock().insert(
+            key,
+            Shared {
+                sender,
+                since: Instant::now(),
+            },
+        );
+    }
+
+    /// claim opening a connection to a mirror, so requests that
+    /// come in meanwhile can share it rather than opening their
+    /// own. if someone else already has, this waits for them and
+    /// gives back None, to check share again
+    pub async fn open(&self, key: &str) -> Option<Opening<'_>> {
+        let lock = {
+            let mut opening = self.opening.lock();
+            match opening.get(key) {
+                Some(lock) => Arc::clone(lock),
+                None => {
+                    let lock = Arc::new(tokio::sync::Mutex::new(()));
+                    let guard = Arc::clone(&lock)
+                        .try_lock_owned()
+                        .expect("nobody else has it yet");
+                    opening.insert(key.to_string(), Arc::clone(&lock));
+                    return Some(Opening {
+                        pool: self,
+                        key: key.to_string(),
+                        lock,
+                        _guard: guard,
+                    });
+                }
+            }
+        };
+        drop(lock.lock().await);
+        None
+    }
+
+    /// drop connections that are no longer usable
+    pub fn prune(&self) {
+        let mut idle = self.idle.lock();
+        for conns in idle.values_mut() {
+            conns.retain(|conn| self.usable(conn));
+        }
+        idle.retain(|_, conns| !conns.is_empty());
+        drop(idle);
+
+        self.shared.lock().retain(|_, conn| self.shareable(conn));
+    }
+
+    /// number of http/1.1 connections to every mirror kept open
+    /// for reuse
+    pub fn idle(&self) -> usize {
+        self.idle.lock().values().map(Vec::len).sum()
+    }
+
+    /// number of http2 connections to every mirror, which may be
+    /// busy with requests while still being shared
+    pub fn shared(&self) -> usize {
+        self.shared.lock().len()
+    }
+}
+
+/// held while opening a connection, letting whoever is waiting
+/// on it go once dropped
+pub struct Opening<'a> {
+    pool: &'a Pool,
+    key: String,
+    lock: Arc<tokio::sync::Mutex<()>>,
+    _guard: OwnedMutexGuard<()>,
+}
+
+impl Drop for Opening<'_> {
+    fn drop(&mut self) {
+        let mut opening = self.pool.opening.lock();
+        if opening
+            .get(&self.key)
+            .is_some_and(|lock| Arc::ptr_eq(lock, &self.lock))
+        {
+            opening.remove(&self.key);
+        }
+    }
+}
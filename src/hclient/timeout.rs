@@ -0,0 +1,200 @@
+use super::Error;
+use crate::metrics::Metrics;
+use hyper::body::{Bytes, Frame, Incoming, SizeHint};
+use std::{
+    future::Future,
+    pin::Pin,
+    str::FromStr,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::time::{Instant, Sleep};
+
+/// how long to wait on a mirror before giving up on it. a zero
+/// duration waits forever
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timeouts {
+    /// for the connection, including any tls handshake
+    pub connect: Duration,
+    /// from sending the request until the response headers arrive
+    pub first_byte: Duration,
+    /// between chunks of the response body
+    pub body: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(10),
+            first_byte: Duration::from_secs(30),
+            body: Duration::from_secs(30),
+        }
+    }
+}
+
+/// timeouts for a single mirror, as MIRROR=STAGE:SECS,... with
+/// stages connect, first-byte and body. unset ones are left as
+/// they are
+#[derive(Clone, Debug, PartialEq)]
+pub struct MirrorTimeouts {
+    pub mirror: String,
+    connect: Option<Duration>,
+    first_byte: Option<Duration>,
+    body: Option<Duration>,
+}
+
+impl MirrorTimeouts {
+    pub fn apply(&self, timeouts: Timeouts) -> Timeouts {
+        Timeouts {
+            connect: self.connect.unwrap_or(timeouts.connect),
+            first_byte: self.first_byte.unwrap_or(timeouts.first_byte),
+            body: self.body.unwrap_or(timeouts.body),
+        }
+    }
+}
+
+impl FromStr for MirrorTimeouts {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mirror, stages) = s.rsplit_once('=').ok_or("expected MIRROR=STAGE:SECS,...")?;
+        let mut timeouts = Self {
+            mirror: mirror.to_string(),
+            connect: None,
+            first_byte: None,
+            body: None,
+        };
+
+        for stage in stages.split(',') {
+            let (name, secs) = stage
+                .split_once(':')
+                .ok_or_else(|| format!("expected STAGE:SECS, got {stage}"))?;
+            let secs = secs.parse().map_err(|e| format!("{secs}: {e}"))?;
+            let slot = match name {
+                "connect" => &mut timeouts.connect,
+                "first-byte" => &mut timeouts.first_byte,
+                "body" => &mut timeouts.body,
+                _ => return Err(format!("expected connect, first-byte or body, got {name}")),
+            };
+            *slot = Some(Duration::from_secs(secs));
+        }
+        Ok(timeouts)
+    }
+}
+
+/// run fut, giving up after limit unless it is zero
+pub async fn within<F: Future>(limit: Duration, fut: F) -> Option<F::Output> {
+    if limit.is_zero() {
+        return Some(fut.await);
+    }
+    tokio::time::timeout(limit, fut).await.ok()
+}
+
+/// log and count a mirror that took too long, returning the
+/// error to fail with
+pub fn timed_out(metrics: &Metrics, mirror: &str, stage: &'static str) -> Error {
+    #[cfg(feature = "log")]
+    eprintln!("{} timed out at {}", mirror, stage);
+
+    metrics.trace_timeout(mirror, stage);
+    format!("timed out at {stage}").into()
+}
+
+/// a response body from a mirror, which fails if the mirror goes
+/// quiet in the middle of it for too long
+pub struct Timed {
+    inner: Incoming,
+    idle: Duration,
+    deadline: Pin<Box<Sleep>>,
+    /// if the deadline counts from when the mirror last went
+    /// quiet, rather than from whenever it was last set
+    armed: bool,
+    mirror: String,
+    metrics: Arc<Metrics>,
+}
+
+impl Timed {
+    pub fn new(inner: Incoming, idle: Duration, mirror: String, metrics: Arc<Metrics>) -> Self {
+        Self {
+            inner,
+            idle,
+            deadline: Box::pin(tokio::time::sleep(idle)),
+            armed: false,
+            mirror,
+            metrics,
+        }
+    }
+}
+
+impl hyper::body::Body for Timed {
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        match Pin::new(&mut self.inner).poll_frame(cx) {
+            Poll::Ready(res) => {
+                self.armed = false;
+                Poll::Ready(res.map(|frame| frame.map_err(Error::from)))
+            }
+            Poll::Pending if self.idle.is_zero() => Poll::Pending,
+            Poll::Pending => {
+                // whoever is reading may have taken a while to ask
+                // for more, which is not the mirror's fault
+                if !self.armed {
+                    let next = Instant::now() + self.idle;
+                    self.deadline.as_mut().reset(next);
+                    self.armed = true;
+                }
+                if self.deadline.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                let e = timed_out(&self.metrics, &self.mirror, "body");
+                Poll::Ready(Some(Err(e)))
+            }
+        }
+    }
+
+    #[inline]
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    #[inline]
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hclient::timeout::*;
+
+    #[test]
+    fn parse() {
+        let t: MirrorTimeouts = "http://a.example=connect:2,body:60".parse().unwrap();
+        assert_eq!(t.mirror, "http://a.example");
+        assert_eq!(
+            t.apply(Timeouts::default()),
+            Timeouts {
+                connect: Duration::from_secs(2),
+                first_byte: Duration::from_secs(30),
+                body: Duration::from_secs(60),
+            }
+        );
+
+        assert!("http://a.example".parse::<MirrorTimeouts>().is_err());
+        assert!("http://a.example=connect"
+            .parse::<MirrorTimeouts>()
+            .is_err());
+        assert!("http://a.example=lunch:5"
+            .parse::<MirrorTimeouts>()
+            .is_err());
+        assert!("http://a.example=body:soon"
+            .parse::<MirrorTimeouts>()
+            .is_err());
+    }
+}
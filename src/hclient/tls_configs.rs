@@ -13,6 +13,13 @@ lazy_static! {
     pub static ref CONF_INSECURE: Arc<ClientConfig> = Arc::new(base_config(true));
 }
 
+/// a copy of config that offers the given protocols over ALPN
+pub fn with_alpn(config: &ClientConfig, protocols: &[&[u8]]) -> Arc<ClientConfig> {
+    let mut config = config.clone();
+    config.alpn_protocols = protocols.iter().map(|p| p.to_vec()).collect();
+    Arc::new(config)
+}
+
 fn base_config(insecure: bool) -> ClientConfig {
     let config = rustls::ClientConfig::builder();
     let config = if insecure {
@@ -0,0 +1,636 @@
+use crate::hclient::Error;
+use http_body_util::BodyExt;
+use hyper::{
+    body::{Body, Bytes, Frame, SizeHint},
+    HeaderMap,
+};
+use parking_lot::Mutex;
+use std::{
+    collections::{HashMap, VecDeque},
+    future::poll_fn,
+    marker::Unpin,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+};
+
+/// upstream fetches currently in progress, so concurrent misses
+/// for the same uri can share a single download
+#[derive(Default)]
+pub struct Inflight {
+    fetches: Mutex<HashMap<String, Arc<Fetch>>>,
+    /// bytes of a fetch to keep around for followers that are yet
+    /// to catch up, after which nobody else may join it
+    max_replay: Option<usize>,
+}
+
+pub enum Joined {
+    /// nobody else is fetching this uri, so the caller should
+    Leader(Leader),
+    /// someone else is already fetching this uri
+    Follower(Follower),
+}
+
+impl Inflight {
+    pub fn new(max_replay: Option<usize>) -> Arc<Self> {
+        Arc::new(Self {
+            fetches: Mutex::default(),
+            max_replay,
+        })
+    }
+
+    pub fn join(self: &Arc<Self>, uri: &str) -> Joined {
+        let mut fetches = self.fetches.lock();
+        if let Some(fetch) = fetches.get(uri) {
+            return Joined::Follower(Follower::new(Arc::clone(fetch)));
+        }
+
+        let fetch = Arc::new(Fetch::default());
+        fetches.insert(uri.to_string(), Arc::clone(&fetch));
+
+        Joined::Leader(Leader {
+            fetch,
+            uri: uri.to_string(),
+            inflight: Arc::clone(self),
+        })
+    }
+
+    /// lead a fetch of uri that nobody else can join, to keep it
+    /// going for the cache without coalescing anything
+    pub fn lead_alone(self: &Arc<Self>, uri: &str) -> Leader {
+        let fetch = Fetch::default();
+        fetch.state.lock().joinable = false;
+        Leader {
+            fetch: Arc::new(fetch),
+            uri: uri.to_string(),
+            inflight: Arc::clone(self),
+        }
+    }
+
+    /// number of uris currently being fetched
+    pub fn len(&self) -> usize {
+        self.fetches.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fetches.lock().is_empty()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Status {
+    /// waiting for upstream to respond
+    Pending,
+    Streaming,
+    Done,
+    Aborted,
+    /// the leader got a body, but is not sharing it
+    Declined,
+}
+
+struct State {
+    status: Status,
+    /// only known once upstream has responded
+    size_hint: Option<SizeHint>,
+    /// what to respond with alongside the body
+    headers: HeaderMap,
+    /// what followers have yet to read, the first being chunk
+    /// number trimmed of the body
+    chunks: VecDeque<Bytes>,
+    trimmed: usize,
+    /// bytes in chunks
+    buffered: usize,
+    /// if followers can still join, and so need every chunk from
+    /// the start
+    joinable: bool,
+    /// the chunk each follower is up to
+    readers: HashMap<u64, usize>,
+    next_reader: u64,
+    wakers: Vec<Waker>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            status: Status::Pending,
+            size_hint: None,
+            headers: HeaderMap::new(),
+            chunks: VecDeque::new(),
+            trimmed: 0,
+            buffered: 0,
+            joinable: true,
+            readers: HashMap::new(),
+            next_reader: 0,
+            wakers: Vec::new(),
+        }
+    }
+}
+
+impl State {
+    fn wake(&mut self) {
+        for waker in self.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+
+    /// drop chunks every follower has read, once nobody new can
+    /// come along needing them
+    fn trim(&mut self) {
+        if self.joinable {
+            return;
+        }
+        let end = self.trimmed + self.chunks.len();
+        let keep = self.readers.values().copied().min().unwrap_or(end);
+        while self.trimmed < keep {
+            let Some(chunk) = self.chunks.pop_front() else {
+                break;
+            };
+            self.buffered -= chunk.len();
+            self.trimmed += 1;
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Fetch {
+    state: Mutex<State>,
+}
+
+/// what a follower gets from a fetch
+pub enum Subscription {
+    /// everything the leader receives, from the very start
+    Shared(Subscriber),
+    /// the leader did not find anything
+    NotFound,
+    /// the leader found something but is not sharing it, so the
+    /// follower has to fetch it itself
+    Unshared,
+}
+
+/// a place in a fetch, holding on to the chunks from there on
+struct Reader {
+    fetch: Arc<Fetch>,
+    id: u64,
+}
+
+impl Drop for Reader {
+    fn drop(&mut self) {
+        let mut state = self.fetch.state.lock();
+        state.readers.remove(&self.id);
+        state.trim();
+    }
+}
+
+/// someone waiting on another's fetch
+pub struct Follower(Reader);
+
+impl Follower {
+    fn new(fetch: Arc<Fetch>) -> Self {
+        let id = {
+            let mut state = fetch.state.lock();
+            let id = state.next_reader;
+            state.next_reader += 1;
+            let start = state.trimmed;
+            state.readers.insert(id, start);
+            id
+        };
+        Self(Reader { fetch, id })
+    }
+
+    /// wait for upstream to respond, to get what the leader does
+    pub async fn subscribe(self) -> Subscription {
+        let fetch = Arc::clone(&self.0.fetch);
+        let published = poll_fn(|cx| {
+            let mut state = fetch.state.lock();
+            match (state.status, &state.size_hint) {
+                (Status::Pending, _) => {
+                    state.wakers.push(cx.waker().clone());
+                    Poll::Pending
+                }
+                (Status::Declined, _) => Poll::Ready(Err(Subscription::Unshared)),
+                (_, None) => Poll::Ready(Err(Subscription::NotFound)),
+                (_, Some(hint)) => Poll::Ready(Ok((hint.clone(), state.headers.clone()))),
+            }
+        })
+        .await;
+
+        match published {
+            Ok((size_hint, headers)) => Subscription::Shared(Subscriber {
+                reader: self.0,
+                sent: 0,
+                size_hint,
+                headers,
+            }),
+            Err(missed) => missed,
+        }
+    }
+}
+
+/// handle for whoever is doing the actual fetch. if dropped
+/// before the body is finished, the fetch is aborted
+pub struct Leader {
+    fetch: Arc<Fetch>,
+    uri: String,
+    inflight: Arc<Inflight>,
+}
+
+impl Leader {
+    /// follow this fetch too, from the start
+    pub fn follow(&self) -> Follower {
+        Follower::new(Arc::clone(&self.fetch))
+    }
+
+    /// start sharing body and the headers to respond with it
+    /// with any followers
+    pub fn publish<T>(self, body: T, headers: HeaderMap) -> Publish<T>
+    where
+        T: Body<Data = Bytes, Error = Error> + Send + Unpin + 'static,
+    {
+        {
+            let mut state = self.fetch.state.lock();
+            state.size_hint = Some(body.size_hint());
+            state.headers = headers;
+            state.status = Status::Streaming;
+            state.wake();
+        }
+
+        Publish {
+            inner: Some((body, self)),
+        }
+    }
+
+    /// give up on sharing, for a body that is only passing
+    /// through. followers go and fetch it themselves
+    pub fn decline(self) {
+        self.finish(Status::Declined);
+    }
+
+    fn finish(&self, status: Status) {
+        {
+            let mut state = self.fetch.state.lock();
+            if matches!(
+                state.status,
+                Status::Done | Status::Aborted | Status::Declined
+            ) {
+                return;
+            }
+            state.status = status;
+            state.wake();
+        }
+        self.close();
+    }
+
+    /// stop anyone else from joining
+    fn close(&self) {
+        {
+            let mut state = self.fetch.state.lock();
+            state.joinable = false;
+            state.trim();
+        }
+
+        let mut fetches = self.inflight.fetches.lock();
+        if fetches
+            .get(&self.uri)
+            .is_some_and(|f| Arc::ptr_eq(f, &self.fetch))
+        {
+            fetches.remove(&self.uri);
+        }
+    }
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        self.finish(Status::Aborted);
+    }
+}
+
+/// the body being fetched, as it is shared with followers
+///
+/// if dropped while followers are still reading, the rest is
+/// fetched for them in the background
+pub struct Publish<T: Body<Data = Bytes, Error = Error> + Send + Unpin + 'static> {
+    /// only taken to hand over in drop
+    inner: Option<(T, Leader)>,
+}
+
+impl<T: Body<Data = Bytes, Error = Error> + Send + Unpin + 'static> Body for Publish<T> {
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let Some((body, leader)) = &mut self.inner else {
+            return Poll::Ready(None);
+        };
+        let res = Pin::new(&mut *body).poll_frame(cx);
+        match res {
+            Poll::Ready(Some(Ok(ref frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    let mut state = leader.fetch.state.lock();
+                    state.chunks.push_back(data.clone());
+                    state.buffered += data.len();
+                    state.trim();
+                    state.wake();
+
+                    // too much to hold on to for whoever might
+                    // come along later
+                    let full = leader
+                        .inflight
+                        .max_replay
+                        .is_some_and(|max| state.buffered > max);
+                    drop(state);
+                    if full {
+                        leader.close();
+                    }
+                }
+                if body.is_end_stream() {
+                    leader.finish(Status::Done);
+                }
+            }
+            Poll::Ready(Some(Err(_))) => leader.finish(Status::Aborted),
+            Poll::Ready(None) => leader.finish(Status::Done),
+            Poll::Pending => (),
+        };
+
+        res
+    }
+
+    #[inline]
+    fn is_end_stream(&self) -> bool {
+        self.inner
+            .as_ref()
+            .is_none_or(|(body, _)| body.is_end_stream())
+    }
+
+    #[inline]
+    fn size_hint(&self) -> SizeHint {
+        self.inner
+            .as_ref()
+            .map_or_else(|| SizeHint::with_exact(0), |(body, _)| body.size_hint())
+    }
+}
+
+impl<T: Body<Data = Bytes, Error = Error> + Send + Unpin + 'static> Publish<T> {
+    /// if anyone is still reading along
+    fn followed(&self) -> bool {
+        self.inner.as_ref().is_some_and(|(_, leader)| {
+            let state = leader.fetch.state.lock();
+            state.status == Status::Streaming && !state.readers.is_empty()
+        })
+    }
+}
+
+impl<T: Body<Data = Bytes, Error = Error> + Send + Unpin + 'static> Drop for Publish<T> {
+    fn drop(&mut self) {
+        if !self.followed() {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+
+        // whoever started the fetch is gone, but the others should
+        // not have to be
+        let mut rest = Publish {
+            inner: self.inner.take(),
+        };
+        runtime.spawn(async move {
+            while rest.followed() {
+                if !matches!(rest.frame().await, Some(Ok(_))) {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+/// body for a follower, replaying everything the leader got
+///
+/// if the leader aborts, this fails once it has replayed what
+/// the leader got before then
+pub struct Subscriber {
+    reader: Reader,
+    sent: u64,
+    size_hint: SizeHint,
+    headers: HeaderMap,
+}
+
+impl Subscriber {
+    /// headers the leader published with the body
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+}
+
+impl Body for Subscriber {
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let fetch = Arc::clone(&self.reader.fetch);
+        let mut state = fetch.state.lock();
+
+        let next = state.readers[&self.reader.id];
+        if let Some(chunk) = state.chunks.get(next - state.trimmed) {
+            let chunk = chunk.clone();
+            state.readers.insert(self.reader.id, next + 1);
+            state.trim();
+            self.sent += chunk.len() as u64;
+            return Poll::Ready(Some(Ok(Frame::data(chunk))));
+        }
+
+        match state.status {
+            Status::Pending | Status::Streaming => {
+                state.wakers.push(cx.waker().clone());
+                Poll::Pending
+            }
+            Status::Done => Poll::Ready(None),
+            Status::Aborted | Status::Declined => {
+                Poll::Ready(Some(Err("upstream fetch was aborted".into())))
+            }
+        }
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        let mut hint = SizeHint::new();
+        hint.set_lower(self.size_hint.lower().saturating_sub(self.sent));
+        if let Some(upper) = self.size_hint.upper() {
+            hint.set_upper(upper.saturating_sub(self.sent));
+        }
+        hint
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::inflight::*;
+    use futures::StreamExt;
+    use http_body_util::{BodyExt, Full, StreamBody};
+
+    fn shared(subscription: Subscription) -> Subscriber {
+        match subscription {
+            Subscription::Shared(subscriber) => subscriber,
+            _ => panic!("should have been shared"),
+        }
+    }
+
+    #[tokio::test]
+    async fn coalesce() {
+        let inflight = Inflight::new(None);
+
+        let Joined::Leader(leader) = inflight.join("/test") else {
+            panic!("first join should lead");
+        };
+        let Joined::Follower(follower) = inflight.join("/test") else {
+            panic!("second join should follow");
+        };
+        assert_eq!(inflight.len(), 1);
+
+        let inp = Full::new(Bytes::from_static(b"yip yap")).map_err(|e| match e {});
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", "text/plain".parse().unwrap());
+        let body = leader.publish(inp, headers.clone());
+        let follower = shared(follower.subscribe().await);
+        assert_eq!(follower.size_hint().exact(), Some(7));
+        assert_eq!(follower.headers(), &headers);
+
+        let res = body.collect().await.unwrap().to_bytes();
+        assert_eq!(res, Bytes::from_static(b"yip yap"));
+        assert!(inflight.is_empty());
+
+        let res = follower.collect().await.unwrap().to_bytes();
+        assert_eq!(res, Bytes::from_static(b"yip yap"));
+    }
+
+    #[tokio::test]
+    async fn abort() {
+        let inflight = Inflight::new(None);
+
+        let Joined::Leader(leader) = inflight.join("/test") else {
+            panic!("first join should lead");
+        };
+        let Joined::Follower(follower) = inflight.join("/test") else {
+            panic!("second join should follow");
+        };
+
+        // leader never found anything
+        drop(leader);
+        assert!(matches!(follower.subscribe().await, Subscription::NotFound));
+        assert!(inflight.is_empty());
+
+        assert!(matches!(inflight.join("/test"), Joined::Leader(_)));
+    }
+
+    #[tokio::test]
+    async fn abort_midway() {
+        let inflight = Inflight::new(None);
+
+        let Joined::Leader(leader) = inflight.join("/test") else {
+            panic!("first join should lead");
+        };
+        let Joined::Follower(follower) = inflight.join("/test") else {
+            panic!("second join should follow");
+        };
+
+        // one chunk and then a failure
+        let chunks = futures::stream::iter([
+            Ok::<_, Error>(Frame::data(Bytes::from_static(b"yip"))),
+            Err("oops".into()),
+        ]);
+        let body = leader.publish(StreamBody::new(chunks), HeaderMap::new());
+        let follower = shared(follower.subscribe().await);
+        assert!(body.collect().await.is_err());
+
+        let mut follower = std::pin::pin!(follower);
+        let frame = follower.frame().await.unwrap().unwrap();
+        assert_eq!(frame.into_data().unwrap(), Bytes::from_static(b"yip"));
+        assert!(follower.frame().await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn leader_gone() {
+        let inflight = Inflight::new(None);
+
+        let Joined::Leader(leader) = inflight.join("/test") else {
+            panic!("first join should lead");
+        };
+        let Joined::Follower(follower) = inflight.join("/test") else {
+            panic!("second join should follow");
+        };
+
+        let chunks = futures::stream::iter([Bytes::from_static(b"yip")])
+            .chain(futures::stream::once(async {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                Bytes::from_static(b"yap")
+            }))
+            .map(|data| Ok::<_, Error>(Frame::data(data)));
+        let mut body = leader.publish(StreamBody::new(Box::pin(chunks)), HeaderMap::new());
+        let follower = shared(follower.subscribe().await);
+        body.frame().await.unwrap().unwrap();
+        // whoever started it went away partway through
+        drop(body);
+
+        let res = follower.collect().await.unwrap().to_bytes();
+        assert_eq!(res, Bytes::from_static(b"yipyap"));
+        assert!(inflight.is_empty());
+    }
+
+    #[tokio::test]
+    async fn declined() {
+        let inflight = Inflight::new(None);
+
+        let Joined::Leader(leader) = inflight.join("/test") else {
+            panic!("first join should lead");
+        };
+        let Joined::Follower(follower) = inflight.join("/test") else {
+            panic!("second join should follow");
+        };
+
+        // only passing through, so the follower is on its own
+        leader.decline();
+        assert!(matches!(follower.subscribe().await, Subscription::Unshared));
+        assert!(inflight.is_empty());
+    }
+
+    #[tokio::test]
+    async fn replay_limit() {
+        let inflight = Inflight::new(Some(4));
+
+        let Joined::Leader(leader) = inflight.join("/test") else {
+            panic!("first join should lead");
+        };
+        let Joined::Follower(follower) = inflight.join("/test") else {
+            panic!("second join should follow");
+        };
+
+        let chunks = ["yip", "yap", "yop"]
+            .map(|chunk| Ok::<_, Error>(Frame::data(Bytes::from_static(chunk.as_bytes()))));
+        let fetch = Arc::clone(&leader.fetch);
+        let body = leader.publish(
+            StreamBody::new(futures::stream::iter(chunks)),
+            HeaderMap::new(),
+        );
+        let mut body = std::pin::pin!(body);
+        let mut follower = std::pin::pin!(shared(follower.subscribe().await));
+
+        body.frame().await.unwrap().unwrap();
+        body.frame().await.unwrap().unwrap();
+        // too much for anyone else to catch up on
+        assert!(matches!(inflight.join("/test"), Joined::Leader(_)));
+        assert_eq!(fetch.state.lock().buffered, 6);
+
+        // so it only holds on to what the follower has yet to read
+        follower.frame().await.unwrap().unwrap();
+        assert_eq!(fetch.state.lock().buffered, 3);
+        body.frame().await.unwrap().unwrap();
+        assert!(body.frame().await.is_none());
+        let rest = follower.collect().await.unwrap().to_bytes();
+        assert_eq!(rest, Bytes::from_static(b"yapyop"));
+        assert_eq!(fetch.state.lock().buffered, 0);
+    }
+}
@@ -1,15 +1,44 @@
-use clap::Parser;
-use http_body_util::{combinators::BoxBody, BodyExt, Full};
-use hyper::{body::Bytes, server::conn::http1, service::service_fn, Request, Response};
+use clap::{Parser, ValueEnum};
+use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full};
+use hyper::{
+    body::{Body, Bytes},
+    header::{self, HeaderName, HeaderValue},
+    server::conn::http1,
+    service::service_fn,
+    HeaderMap, Method, Request, Response, StatusCode,
+};
 use hyper_util::rt::TokioIo;
-use std::{error::Error, net::SocketAddr, sync::Arc};
-use tokio::{net::TcpListener, sync::RwLock};
+use parking_lot::RwLock;
+use std::{
+    error::Error,
+    net::SocketAddr,
+    num::{NonZeroU64, NonZeroUsize},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::net::TcpListener;
 
+pub mod admission;
 pub mod bloom;
 pub mod cache;
+pub mod conditional;
 pub mod hclient;
+pub mod inflight;
 pub mod metrics;
 pub mod ranges;
+pub mod verify;
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum RangeMiss {
+    /// pass the range on to upstream, without caching anything
+    Forward,
+    /// fetch the whole object into the cache, answering with the
+    /// requested bytes as soon as they arrive. ranges of objects
+    /// over --background-fill, or --cache-size if that is unset,
+    /// are forwarded instead
+    Fill,
+}
 
 #[derive(Debug, Parser)]
 struct Opt {
@@ -19,12 +48,154 @@ struct Opt {
     #[arg(short, default_value = "0")]
     skip: usize,
 
+    /// maximum size of the cache in bytes, evicting the least
+    /// recently used objects to stay below it
+    #[arg(short, env = "CACHE_SIZE")]
+    cache_size: Option<usize>,
+
+    /// directory to keep cached objects in, so they survive
+    /// restarts. objects are only kept in memory if unset
+    #[arg(short = 'd', env = "CACHE_DIR")]
+    cache_dir: Option<PathBuf>,
+
+    /// seconds a cached object stays fresh before it is revalidated
+    /// with upstream. objects stay fresh forever if unset
+    #[arg(short, env = "FRESHNESS")]
+    freshness: Option<u64>,
+
+    /// keep filling the cache after the client disconnects or has
+    /// the range it asked for, for objects up to this many bytes
+    #[arg(long, env = "BACKGROUND_FILL")]
+    background_fill: Option<u64>,
+
+    /// what to do with range requests for objects not cached
+    #[arg(long, env = "RANGE_MISS", value_enum, default_value = "forward")]
+    range_miss: RangeMiss,
+
+    /// cache extensions without checking them against their md5
+    /// sidecar first
+    #[arg(long)]
+    no_verify: bool,
+
+    /// cache extensions whose md5 sidecar is missing or unreadable,
+    /// rather than refusing them
+    #[arg(long, env = "ALLOW_MISSING_MD5")]
+    allow_missing_md5: bool,
+
+    /// seconds between forgetting older requests, so only objects
+    /// requested again within this to twice this are cached. 0 to
+    /// never forget
+    #[arg(long, env = "BLOOM_ROTATE", default_value = "86400")]
+    bloom_rotate: u64,
+
+    /// bytes used by each generation of the bloom filter
+    #[arg(long, env = "BLOOM_SIZE", default_value = "8192")]
+    bloom_size: NonZeroUsize,
+
+    /// bits set in the bloom filter for each request
+    #[arg(long, env = "BLOOM_HASHES", default_value = "4", value_parser = clap::value_parser!(u32).range(1..=64))]
+    bloom_hashes: u32,
+
+    /// file to save the bloom filter to, so requests are not
+    /// forgotten across restarts
+    #[arg(long, env = "BLOOM_STATE")]
+    bloom_state: Option<PathBuf>,
+
+    /// seconds between saving the bloom filter
+    #[arg(long, env = "BLOOM_SAVE", default_value = "300")]
+    bloom_save: NonZeroU64,
+
+    /// comma separated upstream headers to pass on to clients and
+    /// keep with cached objects
+    #[arg(
+        long,
+        env = "PASS_HEADERS",
+        value_delimiter = ',',
+        default_value = "content-type",
+        value_parser = passable_header
+    )]
+    pass_headers: Vec<HeaderName>,
+
+    /// idle connections to keep open to each mirror for reuse
+    #[arg(long, env = "POOL_SIZE", default_value = "8")]
+    pool_size: usize,
+
+    /// seconds an idle connection to a mirror is kept open
+    #[arg(long, env = "POOL_IDLE", default_value = "60")]
+    pool_idle: u64,
+
+    /// http version to speak with mirrors
+    #[arg(long, env = "UPSTREAM_VERSION", value_enum, default_value = "auto")]
+    upstream_version: hclient::Version,
+
+    /// redirects to follow from a mirror before giving up on it
+    #[arg(long, env = "MAX_REDIRECTS", default_value = "5")]
+    max_redirects: usize,
+
+    /// seconds to wait for a connection to a mirror, 0 to wait
+    /// forever
+    #[arg(long, env = "CONNECT_TIMEOUT", default_value = "10")]
+    connect_timeout: u64,
+
+    /// seconds to wait for a mirror to start responding, 0 to
+    /// wait forever
+    #[arg(long, env = "FIRST_BYTE_TIMEOUT", default_value = "30")]
+    first_byte_timeout: u64,
+
+    /// seconds a mirror may go quiet in the middle of a body, 0
+    /// to wait forever
+    #[arg(long, env = "BODY_TIMEOUT", default_value = "30")]
+    body_timeout: u64,
+
+    /// timeouts for a specific mirror, as
+    /// MIRROR=STAGE:SECS,... with stages connect, first-byte and
+    /// body
+    #[arg(long, env = "MIRROR_TIMEOUTS", value_delimiter = ';')]
+    mirror_timeouts: Vec<hclient::MirrorTimeouts>,
+
+    /// times to ask the mirrors that failed again, unless another
+    /// one said it does not have the object
+    #[arg(long, env = "RETRIES", default_value = "1")]
+    retries: u32,
+
+    /// milliseconds to wait before the first retry, doubling for
+    /// each one after
+    #[arg(long, env = "RETRY_BACKOFF", default_value = "1000")]
+    retry_backoff: u64,
+
+    /// which upstream responses to cache: second-hit, always,
+    /// size:BYTES, ext[:EXT,...] or tinylfu
+    #[arg(long, env = "ADMISSION", default_value = "second-hit")]
+    admission: admission::Policy,
+
     /// urls to check for a package, in order of precedence
     #[arg(required = true)]
     mirrors: Vec<String>,
 }
 
-fn not_found() -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::http::Error> {
+/// headers that are specific to a connection or that tcrelay
+/// sets itself cannot be passed on from upstream
+fn passable_header(name: &str) -> Result<HeaderName, String> {
+    let name: HeaderName = name.parse().map_err(|e| format!("{e}"))?;
+    let unpassable = [
+        header::ACCEPT_RANGES,
+        header::CONNECTION,
+        header::CONTENT_LENGTH,
+        header::CONTENT_RANGE,
+        header::ETAG,
+        header::LAST_MODIFIED,
+        header::TE,
+        header::TRAILER,
+        header::TRANSFER_ENCODING,
+        header::UPGRADE,
+    ];
+    if unpassable.contains(&name) || name.as_str() == "keep-alive" {
+        return Err(format!("{name} cannot be passed on"));
+    }
+    Ok(name)
+}
+
+fn not_found() -> Result<Response<BoxBody<Bytes, hclient::Error>>, hyper::http::Error> {
     Response::builder()
         .status(hyper::StatusCode::NOT_FOUND)
         .body(
@@ -34,19 +205,104 @@ fn not_found() -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::http::Er
         )
 }
 
-async fn handle_conn(
-    req: Request<impl hyper::body::Body + Send>,
-    mirrors: Arc<Vec<String>>,
-    filter: Arc<RwLock<[u8; 8192]>>,
+fn empty() -> BoxBody<Bytes, hclient::Error> {
+    Empty::new().map_err(|e| match e {}).boxed()
+}
+
+/// copy just the headers in names
+fn pick_headers<'a>(
+    headers: &HeaderMap,
+    names: impl IntoIterator<Item = &'a HeaderName>,
+) -> HeaderMap {
+    let mut picked = HeaderMap::new();
+    for name in names {
+        for value in headers.get_all(name) {
+            picked.append(name, value.clone());
+        }
+    }
+    picked
+}
+
+/// headers to respond to a miss with, so they match what a hit
+/// on the same object will get
+fn miss_headers(meta: &cache::Meta) -> HeaderMap {
+    let mut headers = meta.headers.clone();
+    let strong = meta.etag.as_deref().filter(|etag| !etag.starts_with("W/"));
+    for (name, value) in [
+        (header::ETAG, strong),
+        (header::LAST_MODIFIED, meta.last_modified.as_deref()),
+    ] {
+        if let Some(value) = value.and_then(|v| HeaderValue::from_str(v).ok()) {
+            headers.insert(name, value);
+        }
+    }
+    headers
+}
+
+fn cached_response(
+    headers: &HeaderMap,
+    object: cache::Object,
+) -> Result<Response<BoxBody<Bytes, hclient::Error>>, hyper::http::Error> {
+    let mut res = object
+        .validators
+        .headers(Response::builder().header("Accept-Ranges", "bytes"));
+    if let Some(res_headers) = res.headers_mut() {
+        res_headers.extend(object.meta.headers);
+    }
+
+    if object.validators.not_modified(headers) {
+        return res.status(StatusCode::NOT_MODIFIED).body(empty());
+    }
+
+    ranges::ranged_response(res, &object.content, headers, &object.validators)
+}
+
+/// everything shared between requests
+struct Relay {
+    opt: Opt,
+    filter: Arc<RwLock<bloom::Filter>>,
+    admission: Box<dyn admission::Admission>,
+    client: Arc<hclient::Client>,
     cachestore: Arc<cache::CacheStore>,
+    inflight: Arc<inflight::Inflight>,
+    verifier: Option<Arc<verify::Verifier>>,
     metrics: Arc<metrics::Metrics>,
-    skip: usize,
-) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::http::Error> {
+}
+
+async fn handle_conn(
+    req: Request<impl hyper::body::Body + Send>,
+    relay: Arc<Relay>,
+) -> Result<Response<BoxBody<Bytes, hclient::Error>>, hyper::http::Error> {
+    let Relay {
+        opt,
+        filter,
+        admission,
+        client,
+        cachestore,
+        inflight,
+        verifier,
+        metrics,
+    } = &*relay;
     let uri = req.uri().path();
     metrics.trace_request();
 
     if req.method() == hyper::Method::DELETE {
         metrics.trace_delete();
+
+        if uri.contains('*') {
+            let (count, size) = cachestore.purge(uri);
+            if count == 0 {
+                return not_found();
+            }
+            return Ok(Response::new(
+                Full::new(Bytes::from(format!(
+                    "purged {count} objects freeing {size} B\n"
+                )))
+                .map_err(|e| match e {})
+                .boxed(),
+            ));
+        }
+
         return if cachestore.remove(uri).is_some() {
             Ok(Response::new(
                 Full::new(Bytes::from_static(b"nom nom\n"))
@@ -58,50 +314,299 @@ async fn handle_conn(
         };
     }
 
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header(header::ALLOW, "GET, HEAD, DELETE")
+            .body(empty());
+    }
+
     if uri == "/_tcrelay/metrics" {
+        metrics.set_bloom(filter.read().stats());
+        metrics.set_idle_connections(client.idle());
+        metrics.set_shared_connections(client.shared());
         return Ok(metrics.response());
     }
+    if uri == "/_tcrelay/cache" {
+        return Ok(cachestore.response(req.uri().query()));
+    }
+
+    // checked after the internal endpoints, since those are
+    // small enough to just answer in full
+    if req.method() == Method::HEAD {
+        return head_response(uri, req.headers(), &relay).await;
+    }
 
-    let uri_bytes = uri.as_bytes();
-    let seen = bloom::check(&*filter.read().await, uri_bytes);
+    admission.observe(uri);
 
-    if seen {
-        if let Some(data) = cachestore.get(uri) {
-            let res = Response::builder().header("Accept-Ranges", "bytes");
+    let mut stale = None;
+    if let Some(object) = cachestore.get(uri).await {
+        if !object.stale {
             metrics.trace_hit();
+            return cached_response(req.headers(), object);
+        }
+        stale = Some(object);
+    }
 
-            if let Some(range) = req.headers().get("Range") {
-                return ranges::ranged_response(res, &data, range);
+    let range = req.headers().get(header::RANGE);
+    if range.is_some() && stale.is_none() && opt.range_miss == RangeMiss::Forward {
+        return forward_range(uri, req.headers(), &relay).await;
+    }
+    let fill_range = range.filter(|_| opt.range_miss == RangeMiss::Fill);
+
+    // answer the range once the object is coming in. there is
+    // nothing to check If-Range against before then
+    let respond = |body: BoxBody<Bytes, hclient::Error>,
+                   headers: HeaderMap,
+                   validators: Option<&conditional::Validators>| {
+        let if_range = req.headers().get(header::IF_RANGE);
+        let matches = if_range.is_none_or(|v| validators.is_some_and(|val| val.if_range(v)));
+        let mut res = Response::builder();
+        if let Some(res_headers) = res.headers_mut() {
+            *res_headers = headers;
+        }
+        match fill_range {
+            Some(range) if matches => {
+                ranges::streamed_response(res.header("Accept-Ranges", "bytes"), body, range)
             }
+            _ => res.body(body),
+        }
+    };
 
-            return res.body(Full::new(data).map_err(|e| match e {}).boxed());
+    // revalidating is left uncoalesced, since that is rarely
+    // going to download anything
+    let leader = if stale.is_none() {
+        match inflight.join(uri) {
+            inflight::Joined::Leader(leader) => Some(leader),
+            inflight::Joined::Follower(follower) => match follower.subscribe().await {
+                inflight::Subscription::Shared(body) => {
+                    metrics.trace_coalesce();
+                    let headers = body.headers().clone();
+                    return respond(body.boxed(), headers, None);
+                }
+                inflight::Subscription::NotFound => {
+                    metrics.trace_404();
+                    return not_found();
+                }
+                // not being cached, so not worth holding on to for
+                // anyone else either
+                inflight::Subscription::Unshared => None,
+            },
+        }
+    } else {
+        None
+    };
+
+    let conditions = stale
+        .as_ref()
+        .map(|o| o.meta.conditions())
+        .unwrap_or_default();
+    let upstream = client.try_get(&opt.mirrors, uri, &conditions).await;
+
+    if let Some(object) = stale {
+        let unchanged =
+            matches!(upstream, Some((ref data, _)) if data.status() == StatusCode::NOT_MODIFIED);
+        if unchanged {
+            metrics.trace_revalidate();
+            cachestore.renew(uri).await;
+        }
+
+        // better to serve something old than nothing at all
+        if unchanged || upstream.is_none() {
+            metrics.trace_hit();
+            return cached_response(req.headers(), object);
         }
     }
 
-    if let Some((data, mindex)) = hclient::try_get(&mirrors, uri).await {
+    if let Some((data, mindex)) = upstream {
+        let size = data.body().size_hint().exact();
+        let candidate = admission::Candidate { uri, size };
+        // always ask, since policies may remember being asked.
+        // filling for a range skips asking how popular it is
+        let admitted = match fill_range {
+            Some(_) => admission.admit_fill(&candidate),
+            None => admission.admit(&candidate),
+        };
+        let admit = admitted && mindex >= opt.skip;
+
+        // ranges are only filled for objects that can be finished
+        // once the client has its bytes. anything else is better
+        // off asking upstream for just the range
+        let fill_max = opt.background_fill.or(opt.cache_size.map(|max| max as u64));
+        let fits = size.is_some_and(|len| fill_max.is_none_or(|max| len <= max));
+        if fill_range.is_some() && !(admit && fits) {
+            if let Some(leader) = leader {
+                leader.decline();
+            }
+            drop(data);
+            return forward_range(uri, req.headers(), &relay).await;
+        }
+
         metrics.trace_miss();
+        let meta = cache::Meta {
+            mirror: Some(mindex),
+            headers: pick_headers(data.headers(), &opt.pass_headers),
+            ..cache::Meta::from_headers(data.headers())
+        };
+        let headers = miss_headers(&meta);
         let obody = data.into_body();
-        let body = if seen && mindex >= skip {
+        let validators = conditional::Validators::new(
+            meta.etag.as_deref(),
+            meta.last_modified.as_deref(),
+            std::time::SystemTime::now(),
+            0,
+        );
+        let body = if admit {
             metrics.trace_cache();
-            let sbody = cache::FanoutBody {
-                body: obody,
-                uri: uri.to_string(),
-                buffer: Vec::new(),
-                cachestore,
-            };
-            sbody.boxed()
+            let sbody =
+                cache::FanoutBody::new(obody, uri.to_string(), meta, Arc::clone(cachestore));
+            match verifier {
+                Some(verifier) => sbody.verify(Arc::clone(verifier), mindex).boxed(),
+                None => sbody.boxed(),
+            }
         } else {
-            bloom::add(&mut *filter.write().await, uri_bytes);
             obody.boxed()
         };
 
-        Ok(Response::new(body))
+        // objects of unknown size could be endless, so do not
+        // let those keep downloading with nobody around. ranges
+        // being filled would otherwise be cut short once the
+        // client has its bytes
+        let detach = admit
+            && match (fill_range, opt.background_fill) {
+                (Some(_), _) => true,
+                (None, Some(max)) => body.size_hint().upper().is_some_and(|len| len <= max),
+                (None, None) => false,
+            };
+        if !detach {
+            let leader = match leader {
+                Some(leader) if admit => leader,
+                Some(leader) => {
+                    leader.decline();
+                    return respond(body, headers, Some(&validators));
+                }
+                None => return respond(body, headers, Some(&validators)),
+            };
+            let body = leader.publish(body, headers.clone());
+            return respond(body.boxed(), headers, Some(&validators));
+        }
+
+        // a stale object being replaced is not shared with anyone,
+        // but still has to outlive the client
+        let leader = leader.unwrap_or_else(|| inflight.lead_alone(uri));
+        let follower = leader.follow();
+        let mut body = leader.publish(body, headers.clone());
+        tokio::task::spawn(async move { while let Some(Ok(_)) = body.frame().await {} });
+
+        let inflight::Subscription::Shared(body) = follower.subscribe().await else {
+            unreachable!("fetch was just published");
+        };
+        respond(body.boxed(), headers, Some(&validators))
     } else {
         metrics.trace_404();
         not_found()
     }
 }
 
+/// pass a range request for an uncached object on to upstream,
+/// without caching the partial response
+async fn forward_range(
+    uri: &str,
+    headers: &HeaderMap,
+    relay: &Relay,
+) -> Result<Response<BoxBody<Bytes, hclient::Error>>, hyper::http::Error> {
+    let conditions = pick_headers(headers, &[header::RANGE, header::IF_RANGE]);
+    let Some((data, _)) = relay
+        .client
+        .try_get(&relay.opt.mirrors, uri, &conditions)
+        .await
+    else {
+        relay.metrics.trace_404();
+        return not_found();
+    };
+    relay.metrics.trace_miss();
+
+    let status = data.status();
+    let upstream = pick_headers(
+        data.headers(),
+        [
+            header::ACCEPT_RANGES,
+            header::CONTENT_RANGE,
+            header::ETAG,
+            header::LAST_MODIFIED,
+        ]
+        .iter()
+        .chain(&relay.opt.pass_headers),
+    );
+    let mut res = Response::new(data.into_body().boxed());
+    *res.status_mut() = status;
+    res.headers_mut().extend(upstream);
+    Ok(res)
+}
+
+/// answer a HEAD from what is known about a cached object, or by
+/// asking upstream, without downloading anything either way
+async fn head_response(
+    uri: &str,
+    headers: &HeaderMap,
+    relay: &Relay,
+) -> Result<Response<BoxBody<Bytes, hclient::Error>>, hyper::http::Error> {
+    let cached = relay.cachestore.head(uri);
+    if let Some(head) = cached.as_ref().filter(|h| !h.stale) {
+        relay.metrics.trace_hit();
+        return head_hit(head, headers);
+    }
+
+    let conditions = pick_headers(headers, &[header::IF_NONE_MATCH, header::IF_MODIFIED_SINCE]);
+    let Some((data, _)) = relay
+        .client
+        .try_request(&Method::HEAD, &relay.opt.mirrors, uri, &conditions)
+        .await
+    else {
+        // better to describe something old than nothing at all,
+        // just like a GET would
+        if let Some(head) = cached {
+            relay.metrics.trace_hit();
+            return head_hit(&head, headers);
+        }
+        relay.metrics.trace_404();
+        return not_found();
+    };
+    relay.metrics.trace_miss();
+
+    let mut res = Response::new(empty());
+    *res.status_mut() = data.status();
+    res.headers_mut().extend(pick_headers(
+        data.headers(),
+        [
+            header::ACCEPT_RANGES,
+            header::CONTENT_LENGTH,
+            header::ETAG,
+            header::LAST_MODIFIED,
+        ]
+        .iter()
+        .chain(&relay.opt.pass_headers),
+    ));
+    Ok(res)
+}
+
+/// answer a HEAD for a cached object
+fn head_hit(
+    head: &cache::Head,
+    headers: &HeaderMap,
+) -> Result<Response<BoxBody<Bytes, hclient::Error>>, hyper::http::Error> {
+    let mut res = head
+        .validators
+        .headers(Response::builder().header("Accept-Ranges", "bytes"));
+    if let Some(res_headers) = res.headers_mut() {
+        res_headers.extend(head.headers.clone());
+    }
+    if head.validators.not_modified(headers) {
+        return res.status(StatusCode::NOT_MODIFIED).body(empty());
+    }
+    res.header(header::CONTENT_LENGTH, head.size).body(empty())
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
     let opt = Opt::parse();
@@ -110,30 +615,129 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
 
     eprintln!("listening on {}", listen.local_addr()?);
 
-    let mirrors = Arc::new(opt.mirrors);
-    let filter = Arc::new(RwLock::new([0_u8; 8192]));
-    let cachestore = cache::CacheStore::new();
+    if let Some(ref dir) = opt.cache_dir {
+        std::fs::create_dir_all(dir)?;
+    }
+
     let metrics = metrics::Metrics::new();
+    let cachestore = cache::CacheStore::new(
+        opt.cache_dir.clone(),
+        opt.cache_size,
+        opt.freshness.map(Duration::from_secs),
+        Arc::clone(&metrics),
+    );
+
+    // anything already cached has obviously been seen before
+    let new_filter = || bloom::Filter::new(opt.bloom_size.get(), opt.bloom_hashes);
+    let mut filter = match opt.bloom_state {
+        Some(ref path) => match bloom::Filter::load(path, opt.bloom_size.get(), opt.bloom_hashes) {
+            Ok(filter) => filter,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => new_filter(),
+            Err(_e) => {
+                #[cfg(feature = "log")]
+                eprintln!("discarding bloom state {}: {}", path.display(), _e);
+
+                _ = std::fs::remove_file(path);
+                new_filter()
+            }
+        },
+        None => new_filter(),
+    };
+    for uri in cachestore.load()? {
+        filter.add(uri.as_bytes());
+    }
+    let filter = Arc::new(RwLock::new(filter));
+
+    if opt.bloom_rotate != 0 {
+        let filter = Arc::clone(&filter);
+        let period = Duration::from_secs(opt.bloom_rotate);
+        tokio::task::spawn(async move {
+            let mut interval =
+                tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            loop {
+                interval.tick().await;
+                filter.write().rotate();
+            }
+        });
+    }
+    if let Some(ref path) = opt.bloom_state {
+        let filter = Arc::clone(&filter);
+        let path = path.clone();
+        let period = Duration::from_secs(opt.bloom_save.get());
+        tokio::task::spawn(async move {
+            let mut interval =
+                tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            loop {
+                interval.tick().await;
+                let data = filter.read().to_bytes();
+                let path = path.clone();
+                let res = tokio::task::spawn_blocking(move || bloom::save(&path, &data)).await;
+                if let Ok(Err(_e)) = res {
+                    #[cfg(feature = "log")]
+                    eprintln!("could not save bloom state: {}", _e);
+                }
+            }
+        });
+    }
+
+    let client = hclient::Client::new(
+        hclient::Options {
+            max_idle: opt.pool_size,
+            idle_timeout: Duration::from_secs(opt.pool_idle),
+            version: opt.upstream_version,
+            max_redirects: opt.max_redirects,
+            timeouts: hclient::Timeouts {
+                connect: Duration::from_secs(opt.connect_timeout),
+                first_byte: Duration::from_secs(opt.first_byte_timeout),
+                body: Duration::from_secs(opt.body_timeout),
+            },
+            mirror_timeouts: opt.mirror_timeouts.clone(),
+            retries: opt.retries,
+            backoff: Duration::from_millis(opt.retry_backoff),
+        },
+        Arc::clone(&metrics),
+    );
+    if opt.pool_size != 0 && opt.pool_idle != 0 {
+        let client = Arc::clone(&client);
+        let period = Duration::from_secs(opt.pool_idle);
+        tokio::task::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                client.prune();
+            }
+        });
+    }
+
+    // nothing bigger would be cached anyway
+    let inflight = inflight::Inflight::new(opt.cache_size);
+    let verifier = (!opt.no_verify).then(|| {
+        verify::Verifier::new(
+            opt.mirrors.clone(),
+            Arc::clone(&client),
+            Arc::clone(&cachestore),
+            Arc::clone(&metrics),
+            opt.allow_missing_md5,
+        )
+    });
+    let admission = opt.admission.build(&filter, &cachestore);
+    let relay = Arc::new(Relay {
+        opt,
+        filter,
+        admission,
+        client,
+        cachestore,
+        inflight,
+        verifier,
+        metrics,
+    });
 
     loop {
         let (stream, _) = listen.accept().await?;
         let io = TokioIo::new(stream);
 
-        let mirrors = Arc::clone(&mirrors);
-        let filter = Arc::clone(&filter);
-        let cachestore = Arc::clone(&cachestore);
-        let metrics = Arc::clone(&metrics);
-
-        let service = service_fn(move |req| {
-            handle_conn(
-                req,
-                Arc::clone(&mirrors),
-                Arc::clone(&filter),
-                Arc::clone(&cachestore),
-                Arc::clone(&metrics),
-                opt.skip,
-            )
-        });
+        let relay = Arc::clone(&relay);
+        let service = service_fn(move |req| handle_conn(req, Arc::clone(&relay)));
 
         tokio::task::spawn(async move {
             if let Err(e) = http1::Builder::new().serve_connection(io, service).await {
@@ -146,6 +750,8 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
 #[cfg(test)]
 mod tests {
     use crate::*;
+    use futures::StreamExt;
+    use http_body_util::StreamBody;
 
     #[test]
     fn verify_clap() {
@@ -153,22 +759,76 @@ mod tests {
         Opt::command().debug_assert();
     }
 
-    #[tokio::test]
-    async fn no_mirrors() {
-        use http_body_util::Empty;
-        use hyper::body::Body;
+    /// a relay without any mirrors to ask
+    fn relay() -> Arc<Relay> {
+        let mut opt = Opt::parse_from(["tcrelay", "http://localhost"]);
+        opt.mirrors.clear();
+        build(opt)
+    }
+
+    /// a relay started with args
+    fn relay_with(args: &[&str]) -> Arc<Relay> {
+        build(Opt::parse_from(["tcrelay"].iter().chain(args)))
+    }
 
-        let mirrors = Arc::new(vec![]);
-        let filter = Arc::new(RwLock::new([0_u8; 8192]));
-        let cachestore = cache::CacheStore::new();
+    fn build(opt: Opt) -> Arc<Relay> {
+        let filter = Arc::new(RwLock::new(bloom::Filter::default()));
         let metrics = metrics::Metrics::new();
+        let cachestore = cache::CacheStore::new(
+            None,
+            None,
+            opt.freshness.map(Duration::from_secs),
+            Arc::clone(&metrics),
+        );
+        Arc::new(Relay {
+            admission: opt.admission.build(&filter, &cachestore),
+            client: hclient::Client::new(hclient::Options::default(), Arc::clone(&metrics)),
+            opt,
+            filter,
+            cachestore,
+            inflight: inflight::Inflight::new(None),
+            verifier: None,
+            metrics,
+        })
+    }
 
-        let req = Request::builder()
-            .uri("/meow")
-            .body(Empty::<Bytes>::new())
-            .unwrap();
+    fn request(method: Method, uri: &str) -> Request<Empty<Bytes>> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Empty::new())
+            .unwrap()
+    }
+
+    /// a mirror with "yipyap" at every path, sent in two halves
+    /// with a pause between them, or just "yap" for any range
+    async fn mirror() -> String {
+        hclient::test_server(hyper::Version::HTTP_11, |req| async move {
+            // the only range anyone asks for
+            if req.headers().contains_key(header::RANGE) {
+                let body = Full::new(Bytes::from_static(b"yap")).map_err(|e| match e {});
+                return Response::builder()
+                    .status(StatusCode::PARTIAL_CONTENT)
+                    .header(header::CONTENT_RANGE, "bytes 3-5/6")
+                    .body(body.boxed());
+            }
+            let chunks = futures::stream::iter([Bytes::from_static(b"yip")])
+                .chain(futures::stream::once(async {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    Bytes::from_static(b"yap")
+                }))
+                .map(|data| Ok::<_, hyper::Error>(hyper::body::Frame::data(data)));
+            Response::builder()
+                .header(header::CONTENT_LENGTH, "6")
+                .header(header::ETAG, "\"fox\"")
+                .body(StreamBody::new(chunks).boxed())
+        })
+        .await
+    }
 
-        let res = handle_conn(req, mirrors, filter, cachestore, metrics, 0)
+    #[tokio::test]
+    async fn no_mirrors() {
+        let res = handle_conn(request(Method::GET, "/meow"), relay())
             .await
             .unwrap();
 
@@ -179,4 +839,267 @@ mod tests {
         let res = format!("{res:?}");
         assert_eq!(res, format!("{:?}", not_found().unwrap()));
     }
+
+    #[tokio::test]
+    async fn head() {
+        let relay = relay();
+        let mut meta = cache::Meta::default();
+        meta.headers
+            .insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        relay
+            .cachestore
+            .insert("/meow".to_string(), Bytes::from_static(b"mrrp"), meta);
+
+        let res = handle_conn(request(Method::HEAD, "/meow"), Arc::clone(&relay))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers()["Content-Length"], "4");
+        assert_eq!(res.headers()["Content-Type"], "text/plain");
+        assert_eq!(res.body().size_hint().exact(), Some(0));
+        assert_eq!(relay.cachestore.list("/meow")[0].hits, 0);
+
+        let res = handle_conn(request(Method::HEAD, "/woof"), relay)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn head_stale() {
+        let mut opt = Opt::parse_from(["tcrelay", "-f", "0", "http://localhost"]);
+        opt.mirrors.clear();
+        let relay = build(opt);
+        relay.cachestore.insert(
+            "/meow".to_string(),
+            Bytes::from_static(b"mrrp"),
+            cache::Meta::default(),
+        );
+
+        // nowhere to revalidate it with
+        let res = handle_conn(request(Method::HEAD, "/meow"), Arc::clone(&relay))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers()["Content-Length"], "4");
+    }
+
+    #[tokio::test]
+    async fn multipart_hit() {
+        let relay = relay();
+        let mut meta = cache::Meta::default();
+        meta.headers
+            .insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        relay
+            .cachestore
+            .insert("/meow".to_string(), Bytes::from_static(b"mrrp"), meta);
+
+        let res = handle_conn(ranged("/meow", "bytes=0-0,2-3"), relay)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 206);
+        let types: Vec<_> = res.headers().get_all(header::CONTENT_TYPE).iter().collect();
+        assert_eq!(types.len(), 1);
+        assert!(types[0]
+            .to_str()
+            .unwrap()
+            .starts_with("multipart/byteranges; boundary="));
+    }
+
+    #[test]
+    fn pass_headers() {
+        let opt = Opt::parse_from(["tcrelay", "http://localhost"]);
+        assert_eq!(opt.pass_headers, [header::CONTENT_TYPE]);
+
+        let opt = Opt::parse_from([
+            "tcrelay",
+            "--pass-headers",
+            "Content-Type,cache-control",
+            "http://localhost",
+        ]);
+        assert_eq!(
+            opt.pass_headers,
+            [header::CONTENT_TYPE, header::CACHE_CONTROL]
+        );
+
+        assert!(passable_header("etag").is_err());
+        assert!(passable_header("Transfer-Encoding").is_err());
+        assert!(passable_header("not a header").is_err());
+    }
+
+    #[test]
+    fn miss_headers() {
+        let mut meta = cache::Meta {
+            etag: Some("W/\"fox\"".to_string()),
+            last_modified: Some("Tue, 15 Nov 1994 08:12:31 GMT".to_string()),
+            ..Default::default()
+        };
+        meta.headers
+            .insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+
+        let headers = crate::miss_headers(&meta);
+        assert_eq!(headers["Content-Type"], "text/plain");
+        assert_eq!(headers["Last-Modified"], "Tue, 15 Nov 1994 08:12:31 GMT");
+        // a hit would make up its own
+        assert!(headers.get("ETag").is_none());
+    }
+
+    #[tokio::test]
+    async fn not_allowed() {
+        let res = handle_conn(request(Method::POST, "/meow"), relay())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 405);
+        assert_eq!(res.headers()["Allow"], "GET, HEAD, DELETE");
+    }
+
+    #[tokio::test]
+    async fn background_fill() {
+        let mirror = mirror().await;
+        let relay = relay_with(&[
+            "--admission",
+            "always",
+            "--background-fill",
+            "6",
+            "--no-verify",
+            &mirror,
+        ]);
+        let res = handle_conn(request(Method::GET, "/fox"), Arc::clone(&relay))
+            .await
+            .unwrap();
+        let mut body = res.into_body();
+        let frame = body.frame().await.unwrap().unwrap();
+        assert_eq!(frame.into_data().unwrap(), Bytes::from_static(b"yip"));
+        // the client goes away before the rest arrives
+        drop(body);
+
+        for _ in 0..100 {
+            if relay.cachestore.get("/fox").await.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let object = relay.cachestore.get("/fox").await.unwrap();
+        assert_eq!(object.content, Bytes::from_static(b"yipyap"));
+
+        // too big to keep going with nobody waiting for it
+        let relay = relay_with(&[
+            "--admission",
+            "always",
+            "--background-fill",
+            "5",
+            "--no-verify",
+            &mirror,
+        ]);
+        let res = handle_conn(request(Method::GET, "/fox"), Arc::clone(&relay))
+            .await
+            .unwrap();
+        let mut body = res.into_body();
+        body.frame().await.unwrap().unwrap();
+        drop(body);
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(relay.cachestore.get("/fox").await.is_none());
+    }
+
+    /// wait a bit for uri to be cached
+    async fn cached(relay: &Relay, uri: &str) -> Option<Bytes> {
+        for _ in 0..20 {
+            if let Some(object) = relay.cachestore.get(uri).await {
+                return Some(object.content);
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        None
+    }
+
+    fn ranged(uri: &str, range: &'static str) -> Request<Empty<Bytes>> {
+        let mut req = request(Method::GET, uri);
+        req.headers_mut()
+            .insert(header::RANGE, HeaderValue::from_static(range));
+        req
+    }
+
+    #[tokio::test]
+    async fn range_forward() {
+        let mirror = mirror().await;
+        let relay = relay_with(&["--admission", "always", "--no-verify", &mirror]);
+        let res = handle_conn(ranged("/fox", "bytes=3-"), Arc::clone(&relay))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 206);
+        assert_eq!(res.headers()["Content-Range"], "bytes 3-5/6");
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, Bytes::from_static(b"yap"));
+        assert_eq!(cached(&relay, "/fox").await, None);
+    }
+
+    #[tokio::test]
+    async fn range_fill() {
+        let mirror = mirror().await;
+        let args = ["--range-miss", "fill", "--no-verify", "-f", "0", &mirror];
+        let relay = relay_with(&args);
+        let res = handle_conn(ranged("/fox", "bytes=0-2"), Arc::clone(&relay))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 206);
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, Bytes::from_static(b"yip"));
+        assert_eq!(
+            cached(&relay, "/fox").await,
+            Some(Bytes::from_static(b"yipyap"))
+        );
+
+        // replacing a stale copy keeps going just the same
+        relay.cachestore.insert(
+            "/wolf".to_string(),
+            Bytes::from_static(b"old"),
+            cache::Meta::default(),
+        );
+        let res = handle_conn(ranged("/wolf", "bytes=0-2"), Arc::clone(&relay))
+            .await
+            .unwrap();
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, Bytes::from_static(b"yip"));
+        for _ in 0..20 {
+            if cached(&relay, "/wolf").await.unwrap() != "old" {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(
+            cached(&relay, "/wolf").await,
+            Some(Bytes::from_static(b"yipyap"))
+        );
+
+        // unless it is bigger than what may be filled without a
+        // client waiting on it
+        for limit in [["--background-fill", "5"], ["-c", "5"]] {
+            let relay = relay_with(&[&limit[..], &args[..]].concat());
+            let res = handle_conn(ranged("/fox", "bytes=3-"), Arc::clone(&relay))
+                .await
+                .unwrap();
+            assert_eq!(res.headers()["Content-Range"], "bytes 3-5/6");
+            let body = res.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(body, Bytes::from_static(b"yap"));
+            assert_eq!(cached(&relay, "/fox").await, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn range_fill_admission() {
+        let mirror = mirror().await;
+        // only how popular it is goes unasked, the rest have the
+        // range forwarded
+        for policy in ["size:5", "ext"] {
+            let args = ["--range-miss", "fill", "--admission", policy];
+            let relay = relay_with(&[&args[..], &["--no-verify", &mirror]].concat());
+            let res = handle_conn(ranged("/fox", "bytes=3-"), Arc::clone(&relay))
+                .await
+                .unwrap();
+            let body = res.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(body, Bytes::from_static(b"yap"));
+            assert_eq!(cached(&relay, "/fox").await, None);
+        }
+    }
 }
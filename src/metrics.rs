@@ -1,8 +1,13 @@
 use http_body_util::{combinators::BoxBody, BodyExt, Full};
 use hyper::{body::Bytes, Response};
-use std::sync::{
-    atomic::{AtomicUsize, Ordering::Relaxed},
-    Arc,
+use parking_lot::Mutex;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering::Relaxed},
+        Arc,
+    },
 };
 
 #[derive(Default)]
@@ -13,6 +18,24 @@ pub struct Metrics {
     cached: AtomicUsize,
     deletes: AtomicUsize,
     not_found: AtomicUsize,
+    evictions: AtomicUsize,
+    revalidated: AtomicUsize,
+    coalesced: AtomicUsize,
+    aborted: AtomicUsize,
+    md5_mismatches: AtomicUsize,
+    md5_missing: AtomicUsize,
+    connections_opened: AtomicUsize,
+    connections_reused: AtomicUsize,
+    resumed: AtomicUsize,
+    idle_connections: AtomicUsize,
+    shared_connections: AtomicUsize,
+    /// f64 bits, as there is no AtomicF64
+    bloom_fill: AtomicU64,
+    bloom_fpr: AtomicU64,
+    /// per mirror
+    redirects: Mutex<BTreeMap<String, usize>>,
+    /// per mirror and stage
+    timeouts: Mutex<BTreeMap<(String, &'static str), usize>>,
 }
 
 macro_rules! trace_functions {
@@ -33,24 +56,88 @@ impl Metrics {
     }
 
     pub fn output(&self) -> String {
-        format!(
+        let mut output = format!(
             r"requests {}
 hits {}
 misses {}
 cached {}
 deletes {}
 not_found {}
+evictions {}
+revalidated {}
+coalesced {}
+aborted {}
+md5_mismatches {}
+md5_missing {}
+connections_opened {}
+connections_reused {}
+resumed {}
+idle_connections {}
+shared_connections {}
+bloom_fill {:.4}
+bloom_fpr {:.4}
 ",
             self.requests.load(Relaxed),
             self.hits.load(Relaxed),
             self.misses.load(Relaxed),
             self.cached.load(Relaxed),
             self.deletes.load(Relaxed),
-            self.not_found.load(Relaxed)
-        )
+            self.not_found.load(Relaxed),
+            self.evictions.load(Relaxed),
+            self.revalidated.load(Relaxed),
+            self.coalesced.load(Relaxed),
+            self.aborted.load(Relaxed),
+            self.md5_mismatches.load(Relaxed),
+            self.md5_missing.load(Relaxed),
+            self.connections_opened.load(Relaxed),
+            self.connections_reused.load(Relaxed),
+            self.resumed.load(Relaxed),
+            self.idle_connections.load(Relaxed),
+            self.shared_connections.load(Relaxed),
+            f64::from_bits(self.bloom_fill.load(Relaxed)),
+            f64::from_bits(self.bloom_fpr.load(Relaxed))
+        );
+        for (mirror, count) in self.redirects.lock().iter() {
+            let _ = writeln!(output, "redirects{{mirror=\"{mirror}\"}} {count}");
+        }
+        for ((mirror, stage), count) in self.timeouts.lock().iter() {
+            let _ = writeln!(
+                output,
+                "timeouts{{mirror=\"{mirror}\",stage=\"{stage}\"}} {count}"
+            );
+        }
+        output
     }
 
-    pub fn response(&self) -> Response<BoxBody<Bytes, hyper::Error>> {
+    /// record the state of the bloom filter, as given by
+    /// bloom::Filter::stats
+    pub fn set_bloom(&self, (fill, fpr): (f64, f64)) {
+        self.bloom_fill.store(fill.to_bits(), Relaxed);
+        self.bloom_fpr.store(fpr.to_bits(), Relaxed);
+    }
+
+    pub fn set_idle_connections(&self, idle: usize) {
+        self.idle_connections.store(idle, Relaxed);
+    }
+
+    pub fn set_shared_connections(&self, shared: usize) {
+        self.shared_connections.store(shared, Relaxed);
+    }
+
+    pub fn trace_redirect(&self, mirror: &str) {
+        *self.redirects.lock().entry(mirror.to_string()).or_default() += 1;
+    }
+
+    /// stage is connect, first-byte or body
+    pub fn trace_timeout(&self, mirror: &str, stage: &'static str) {
+        *self
+            .timeouts
+            .lock()
+            .entry((mirror.to_string(), stage))
+            .or_default() += 1;
+    }
+
+    pub fn response(&self) -> Response<BoxBody<Bytes, crate::hclient::Error>> {
         Response::new(
             Full::new(Bytes::from(self.output()))
                 .map_err(|e| match e {})
@@ -64,7 +151,16 @@ not_found {}
         (trace_miss, misses),
         (trace_cache, cached),
         (trace_delete, deletes),
-        (trace_404, not_found)
+        (trace_404, not_found),
+        (trace_evict, evictions),
+        (trace_revalidate, revalidated),
+        (trace_coalesce, coalesced),
+        (trace_abort, aborted),
+        (trace_md5_mismatch, md5_mismatches),
+        (trace_md5_missing, md5_missing),
+        (trace_conn_open, connections_opened),
+        (trace_conn_reuse, connections_reused),
+        (trace_resume, resumed)
     );
 }
 
@@ -94,16 +190,71 @@ mod tests {
         for _ in 0..115 {
             m.trace_404()
         }
+        for _ in 0..107 {
+            m.trace_evict()
+        }
+        for _ in 0..104 {
+            m.trace_revalidate()
+        }
+        for _ in 0..108 {
+            m.trace_coalesce()
+        }
+        for _ in 0..109 {
+            m.trace_abort()
+        }
+        for _ in 0..103 {
+            m.trace_md5_mismatch()
+        }
+        for _ in 0..118 {
+            m.trace_md5_missing()
+        }
+        for _ in 0..112 {
+            m.trace_conn_open()
+        }
+        for _ in 0..113 {
+            m.trace_conn_reuse()
+        }
+        for _ in 0..117 {
+            m.trace_resume()
+        }
+        m.set_idle_connections(6);
+        m.set_shared_connections(2);
+        for _ in 0..114 {
+            m.trace_redirect("http://b.example")
+        }
+        m.trace_redirect("http://a.example");
+        for _ in 0..116 {
+            m.trace_timeout("http://a.example", "connect")
+        }
+        m.trace_timeout("http://a.example", "body");
+        m.set_bloom((0.5, 0.0625));
 
         assert_eq!(
             m.output(),
-            r"requests 102
+            r#"requests 102
 hits 111
 misses 120
 cached 105
 deletes 101
 not_found 115
-"
+evictions 107
+revalidated 104
+coalesced 108
+aborted 109
+md5_mismatches 103
+md5_missing 118
+connections_opened 112
+connections_reused 113
+resumed 117
+idle_connections 6
+shared_connections 2
+bloom_fill 0.5000
+bloom_fpr 0.0625
+redirects{mirror="http://a.example"} 1
+redirects{mirror="http://b.example"} 114
+timeouts{mirror="http://a.example",stage="body"} 1
+timeouts{mirror="http://a.example",stage="connect"} 116
+"#
         );
     }
 }
@@ -1,58 +1,263 @@
+use crate::{bloom, conditional::Validators, hclient};
 use http_body_util::{combinators::BoxBody, BodyExt, Full};
-use hyper::{body::Bytes, header::HeaderValue, Response};
-use std::{cmp::min, ops::RangeInclusive};
+use hyper::{
+    body::{Body, Bytes, Frame, SizeHint},
+    header::{self, HeaderMap, HeaderValue},
+    Response,
+};
+use std::{
+    cmp::{max, min},
+    collections::VecDeque,
+    ops::RangeInclusive,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering::Relaxed},
+    task::{Context, Poll},
+    time::SystemTime,
+};
 
-/// read the value of an HTTP Range header and parse into a range
-///
-/// will not return an empty or out of bounds range, should be
-/// fine to pass to Bytes.slice after unwrapping
-pub fn parse(range: &HeaderValue, len: usize) -> Option<RangeInclusive<usize>> {
-    if len == 0 {
+/// most ranges accepted in a single request, since each one is
+/// extra work and nothing legitimate needs that many
+pub const MAX_RANGES: usize = 16;
+
+/// parse a position or suffix length in a range, saturating
+/// rather than failing since huge positions are just out of bounds
+fn position(digits: &[u8]) -> Option<usize> {
+    if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
         return None;
     }
+    Some(digits.iter().fold(0_usize, |acc, c| {
+        acc.saturating_mul(10).saturating_add((c - b'0') as usize)
+    }))
+}
 
-    let range = range.as_ref();
+#[derive(Debug, PartialEq)]
+pub enum Ranges {
+    /// not something we understand, so it should be ignored and
+    /// the whole thing served
+    Invalid,
+    /// none of the ranges overlap the content
+    Unsatisfiable,
+    /// sorted, merged and never empty or out of bounds, should be
+    /// fine to pass to Bytes.slice
+    Satisfiable(Vec<RangeInclusive<usize>>),
+}
 
-    if !range.starts_with(b"bytes=") {
-        return None;
-    }
+/// read the value of an HTTP Range header and parse into ranges
+/// within content of len bytes
+///
+/// overlapping and adjacent ranges are merged
+pub fn parse(range: &HeaderValue, len: usize) -> Ranges {
+    let range = range.as_bytes();
+    // range units are case insensitive
+    let Some(range) = range
+        .get(..6)
+        .filter(|unit| unit.eq_ignore_ascii_case(b"bytes="))
+        .map(|_| &range[6..])
+    else {
+        return Ranges::Invalid;
+    };
 
-    let mut range = range[6..].iter();
-    let mut left: usize = 0;
-    let mut right: Option<usize> = None;
+    let mut ranges = Vec::new();
+    let mut specs = 0;
+    for spec in range.split(|c| *c == b',') {
+        let spec = spec.trim_ascii();
+        // empty list elements are allowed, for some reason
+        if spec.is_empty() {
+            continue;
+        }
+        specs += 1;
+        if specs > MAX_RANGES {
+            return Ranges::Invalid;
+        }
+
+        let Some(dash) = spec.iter().position(|c| *c == b'-') else {
+            return Ranges::Invalid;
+        };
+        let (left, right) = (&spec[..dash], &spec[dash + 1..]);
 
-    for c in &mut range {
-        if !c.is_ascii_digit() {
-            if c != &b'-' {
-                return None;
+        // a suffix range, for the last right bytes
+        if left.is_empty() {
+            let Some(suffix) = position(right) else {
+                return Ranges::Invalid;
+            };
+            if suffix != 0 && len != 0 {
+                ranges.push(len.saturating_sub(suffix)..=len - 1);
             }
-            break;
+            continue;
+        }
+
+        let Some(left) = position(left) else {
+            return Ranges::Invalid;
+        };
+        let right = match right {
+            b"" => None,
+            right => match position(right) {
+                Some(right) if right >= left => Some(right),
+                _ => return Ranges::Invalid,
+            },
+        };
+
+        // out of bounds ranges are skipped, as long as another
+        // one is satisfiable
+        if left >= len {
+            continue;
         }
-        left = left * 10 + (c - b'0') as usize;
+
+        // http ranges are inclusive
+        ranges.push(left..=min(right.unwrap_or(len - 1), len - 1));
+    }
+
+    if specs == 0 {
+        return Ranges::Invalid;
     }
-    for c in range {
-        if !c.is_ascii_digit() {
-            if !b", ".contains(c) {
-                return None;
+
+    ranges.sort_by_key(|r| *r.start());
+    let mut merged: Vec<RangeInclusive<usize>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if *range.start() <= last.end() + 1 => {
+                *last = *last.start()..=max(*last.end(), *range.end());
             }
-            break;
+            _ => merged.push(range),
         }
-        right = Some(right.unwrap_or(0) * 10 + (c - b'0') as usize);
     }
 
-    // http ranges are inclusive
-    let len = len - 1;
+    if merged.is_empty() {
+        Ranges::Unsatisfiable
+    } else {
+        Ranges::Satisfiable(merged)
+    }
+}
 
-    let right = min(right.unwrap_or(len), len);
-    if left > right {
-        return None;
+/// a body made up of several chunks, so the parts of a multipart
+/// response do not need to be copied into one buffer
+struct Chunks {
+    chunks: VecDeque<Bytes>,
+    remaining: u64,
+}
+
+impl Body for Chunks {
+    type Data = Bytes;
+    type Error = hclient::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let Some(chunk) = self.chunks.pop_front() else {
+            return Poll::Ready(None);
+        };
+        self.remaining -= chunk.len() as u64;
+        Poll::Ready(Some(Ok(Frame::data(chunk))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.chunks.is_empty()
     }
 
-    Some(left..=right)
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::with_exact(self.remaining)
+    }
+}
+
+/// something that will not show up in the content by accident
+fn boundary() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let seed = COUNTER.fetch_add(1, Relaxed);
+    format!("tcrelay-{:016x}", bloom::hash(seed, &nanos.to_le_bytes()))
+}
+
+fn content_range(range: &RangeInclusive<usize>, len: usize) -> String {
+    format!("bytes {}-{}/{}", range.start(), range.end(), len)
+}
+
+/// a multipart/byteranges response with a part for each range
+fn multipart_response(
+    res: http::response::Builder,
+    data: &Bytes,
+    ranges: &[RangeInclusive<usize>],
+) -> Result<Response<BoxBody<Bytes, hclient::Error>>, hyper::http::Error> {
+    let boundary = boundary();
+
+    let mut chunks = VecDeque::with_capacity(ranges.len() * 2 + 1);
+    for range in ranges {
+        chunks.push_back(Bytes::from(format!(
+            "\r\n--{boundary}\r\nContent-Range: {}\r\n\r\n",
+            content_range(range, data.len())
+        )));
+        chunks.push_back(data.slice(range.clone()));
+    }
+    chunks.push_back(Bytes::from(format!("\r\n--{boundary}--\r\n")));
+
+    let remaining = chunks.iter().map(|c| c.len() as u64).sum();
+    let mut res = res.status(hyper::StatusCode::PARTIAL_CONTENT);
+    // in place of whatever type the object itself has
+    if let Some(headers) = res.headers_mut() {
+        let content_type = format!("multipart/byteranges; boundary={boundary}");
+        headers.insert(header::CONTENT_TYPE, HeaderValue::try_from(content_type)?);
+    }
+    res.body(Chunks { chunks, remaining }.boxed())
+}
+
+/// a body of only the bytes in a single range of another body
+struct Slice<B> {
+    body: B,
+    skip: u64,
+    remaining: u64,
 }
 
-fn not_satisfiable() -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::http::Error> {
-    Response::builder()
+impl<B: Body<Data = Bytes, Error = hclient::Error> + Unpin> Body for Slice<B> {
+    type Data = Bytes;
+    type Error = hclient::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        while self.remaining != 0 {
+            let frame = match Pin::new(&mut self.body).poll_frame(cx) {
+                Poll::Ready(Some(Ok(frame))) => frame,
+                other => return other,
+            };
+            let Ok(mut data) = frame.into_data() else {
+                continue;
+            };
+
+            let len = data.len() as u64;
+            if self.skip >= len {
+                self.skip -= len;
+                continue;
+            }
+            data = data.slice(self.skip as usize..);
+            self.skip = 0;
+            data.truncate(min(data.len() as u64, self.remaining) as usize);
+            self.remaining -= data.len() as u64;
+
+            return Poll::Ready(Some(Ok(Frame::data(data))));
+        }
+
+        Poll::Ready(None)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.remaining == 0
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::with_exact(self.remaining)
+    }
+}
+
+fn not_satisfiable(
+    res: http::response::Builder,
+    len: usize,
+) -> Result<Response<BoxBody<Bytes, hclient::Error>>, hyper::http::Error> {
+    res.header("Content-Range", format!("bytes */{len}"))
         .status(hyper::StatusCode::RANGE_NOT_SATISFIABLE)
         .body(
             Full::new(Bytes::from_static(b"U WOT M8\n"))
@@ -61,92 +266,283 @@ fn not_satisfiable() -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::ht
         )
 }
 
+/// respond with the parts of data asked for by the Range in
+/// headers, or all of it if there is none or its If-Range does
+/// not match
 pub fn ranged_response(
     res: http::response::Builder,
     data: &Bytes,
-    range: &HeaderValue,
-) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::http::Error> {
+    headers: &HeaderMap,
+    validators: &Validators,
+) -> Result<Response<BoxBody<Bytes, hclient::Error>>, hyper::http::Error> {
     let olength = data.len();
 
-    let Some(range) = parse(range, olength) else {
-        return not_satisfiable();
+    let range = headers.get(header::RANGE).filter(|_| {
+        headers
+            .get(header::IF_RANGE)
+            .is_none_or(|v| validators.if_range(v))
+    });
+    let Some(range) = range else {
+        return res.body(Full::new(data.clone()).map_err(|e| match e {}).boxed());
+    };
+
+    let ranges = match parse(range, olength) {
+        Ranges::Satisfiable(ranges) => ranges,
+        Ranges::Unsatisfiable => return not_satisfiable(res, olength),
+        Ranges::Invalid => {
+            return res.body(Full::new(data.clone()).map_err(|e| match e {}).boxed())
+        }
+    };
+    let [range] = &ranges[..] else {
+        return multipart_response(res, data, &ranges);
     };
 
     let res = res
-        .header(
-            "Content-Range",
-            format!("{}-{}/{}", range.start(), range.end(), olength),
-        )
+        .header("Content-Range", content_range(range, olength))
         .status(hyper::StatusCode::PARTIAL_CONTENT);
-    let data = data.slice(range);
+    let data = data.slice(range.clone());
 
     res.body(Full::new(data).map_err(|e| match e {}).boxed())
 }
 
+/// answer a Range from content that is still coming in, without
+/// waiting for all of it
+///
+/// only works for a single range of a body with a known length,
+/// anything else gets the whole body
+pub fn streamed_response<B>(
+    res: http::response::Builder,
+    body: B,
+    range: &HeaderValue,
+) -> Result<Response<BoxBody<Bytes, hclient::Error>>, hyper::http::Error>
+where
+    B: Body<Data = Bytes, Error = hclient::Error> + Send + Sync + Unpin + 'static,
+{
+    let Some(olength) = body.size_hint().exact() else {
+        return res.body(body.boxed());
+    };
+    let olength = olength as usize;
+
+    match parse(range, olength) {
+        Ranges::Satisfiable(ranges) if ranges.len() == 1 => {
+            let range = &ranges[0];
+            let body = Slice {
+                body,
+                skip: *range.start() as u64,
+                remaining: (range.end() - range.start() + 1) as u64,
+            };
+            res.header("Content-Range", content_range(range, olength))
+                .status(hyper::StatusCode::PARTIAL_CONTENT)
+                .body(body.boxed())
+        }
+        Ranges::Unsatisfiable => not_satisfiable(res, olength),
+        _ => res.body(body.boxed()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::ranges::*;
     use hyper::body::Body;
 
-    macro_rules! assert_parse {
-        ($inp:expr, $len:expr, $expect:expr) => {
-            assert_eq!(parse(&HeaderValue::from_static($inp), $len), $expect);
-        };
-        ($(($inp:expr, $len:expr, $expect:expr)),*) => {$(
-            assert_parse!($inp, $len, Some($expect));
-        )*};
-        ($(($inp:expr, $len:expr)),*) => {$(
-            assert_parse!($inp, $len, None);
-        )*};
+    /// what RFC 9110 says each Range header should parse to
+    #[test]
+    fn conformance() {
+        use Ranges::*;
+
+        let table: &[(&str, usize, Ranges)] = &[
+            ("bytes=0-0", 1, Satisfiable(vec![0..=0])),
+            ("bytes=0-", 43, Satisfiable(vec![0..=42])),
+            ("bytes=5-9", 10, Satisfiable(vec![5..=9])),
+            ("bytes=5-10", 10, Satisfiable(vec![5..=9])),
+            ("bytes=5-90", 10, Satisfiable(vec![5..=9])),
+            ("bytes=5-90 ", 10, Satisfiable(vec![5..=9])),
+            ("Bytes=5-9", 10, Satisfiable(vec![5..=9])),
+            ("bytes=555555-", 1000000, Satisfiable(vec![555555..=999999])),
+            ("bytes=99999999999999999999999-", 10, Unsatisfiable),
+            // suffixes
+            ("bytes=-3", 10, Satisfiable(vec![7..=9])),
+            ("bytes=-10", 10, Satisfiable(vec![0..=9])),
+            ("bytes=-30", 10, Satisfiable(vec![0..=9])),
+            ("bytes=-0", 10, Unsatisfiable),
+            ("bytes=-3", 0, Unsatisfiable),
+            // multiple ranges
+            ("bytes=0-1, 5-6", 10, Satisfiable(vec![0..=1, 5..=6])),
+            ("bytes=5-6,0-1", 10, Satisfiable(vec![0..=1, 5..=6])),
+            ("bytes=0-4,2-6", 10, Satisfiable(vec![0..=6])),
+            ("bytes=5-90, 4-5", 10, Satisfiable(vec![4..=9])),
+            ("bytes=0-4,5-6,8-", 10, Satisfiable(vec![0..=6, 8..=9])),
+            ("bytes=0-1,-2", 10, Satisfiable(vec![0..=1, 8..=9])),
+            ("bytes=-5,3-4", 10, Satisfiable(vec![3..=9])),
+            ("bytes=2-3,50-60", 10, Satisfiable(vec![2..=3])),
+            ("bytes=2-3,,", 10, Satisfiable(vec![2..=3])),
+            ("bytes=50-60,70-", 10, Unsatisfiable),
+            // nothing to satisfy them with
+            ("bytes=0-0", 0, Unsatisfiable),
+            ("bytes=69-", 42, Unsatisfiable),
+            ("bytes=69-420", 31, Unsatisfiable),
+            // not valid ranges at all
+            ("bytes=420-69", 621, Invalid),
+            ("bytes=2-3,4-1", 10, Invalid),
+            ("bytes=3-3.14", 3621, Invalid),
+            ("bytes=2-3,yip", 10, Invalid),
+            ("bytes=1-2-3", 10, Invalid),
+            ("bytes=-", 10, Invalid),
+            ("bytes=--1", 10, Invalid),
+            ("bytes=", 10, Invalid),
+            ("bytes=,", 10, Invalid),
+            ("boots=5-9", 10, Invalid),
+            ("bytes==5-9", 10, Invalid),
+            ("bytes 5-9", 10, Invalid),
+        ];
+
+        for (inp, len, expect) in table {
+            assert_eq!(
+                &parse(&HeaderValue::from_static(inp), *len),
+                expect,
+                "{inp} with length {len}"
+            );
+        }
     }
 
     #[test]
-    fn valid_ranges() {
-        assert_parse!(
-            ("bytes=0-0", 1, 0..=0),
-            ("bytes=0-", 43, 0..=42),
-            ("bytes=5-9", 10, 5..=9),
-            ("bytes=5-10", 10, 5..=9),
-            ("bytes=5-90", 10, 5..=9),
-            ("bytes=5-90, 4-5", 10, 5..=9),
-            ("bytes=5-90 ", 10, 5..=9),
-            ("bytes=555555-", 1000000, 555555..=999999)
+    fn too_many_ranges() {
+        let many = |n| HeaderValue::from_str(&format!("bytes={}", vec!["0-0"; n].join(",")));
+        assert_eq!(
+            parse(&many(MAX_RANGES).unwrap(), 10),
+            Ranges::Satisfiable(vec![0..=0])
         );
+        assert_eq!(parse(&many(MAX_RANGES + 1).unwrap(), 10), Ranges::Invalid);
     }
 
-    #[test]
-    fn invalid_ranges() {
-        assert_parse!(
-            ("bytes=0-0", 0),
-            ("bytes=69-", 42),
-            ("bytes=69-420", 31),
-            ("bytes=420-69", 621),
-            ("bytes=3-3.14", 3621),
-            ("boots=5-9", 10),
-            ("bytes==5-9", 10)
+    /// respond to a request with headers for b"beep boop"
+    fn request(
+        headers: &[(&'static str, &'static str)],
+    ) -> Response<BoxBody<Bytes, hclient::Error>> {
+        let headers = headers
+            .iter()
+            .map(|(k, v)| {
+                (
+                    header::HeaderName::from_static(k),
+                    HeaderValue::from_static(v),
+                )
+            })
+            .collect();
+        let validators = Validators::new(
+            Some("\"fox\""),
+            Some("Tue, 15 Nov 1994 08:12:31 GMT"),
+            SystemTime::now(),
+            9,
         );
+        let data = Bytes::from_static(b"beep boop");
+        let res = Response::builder().header("Accept-Ranges", "bytes");
+        ranged_response(res, &data, &headers, &validators).unwrap()
     }
 
     #[test]
     fn valid_request() {
-        let range = HeaderValue::from_static("bytes=3-5");
-        let data = Bytes::from_static(b"beep boop");
-        let res = Response::builder().header("Accept-Ranges", "bytes");
-        let res = ranged_response(res, &data, &range).unwrap();
+        let res = request(&[("range", "bytes=3-5")]);
         assert_eq!(res.status(), 206);
         assert_eq!(
             res.headers().get("Content-Range"),
-            Some(&HeaderValue::from_static("3-5/9"))
+            Some(&HeaderValue::from_static("bytes 3-5/9"))
         );
         assert_eq!(res.body().size_hint().exact(), Some(3));
     }
 
+    #[tokio::test]
+    async fn multipart_request() {
+        let res = request(&[("range", "bytes=0-3,5-")]);
+        assert_eq!(res.status(), 206);
+
+        let ctype = res.headers()["Content-Type"].to_str().unwrap();
+        let boundary = ctype
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap()
+            .to_string();
+        let len = res.body().size_hint().exact().unwrap();
+
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body.len() as u64, len);
+        assert_eq!(
+            body,
+            format!(
+                "\r\n--{boundary}\r\nContent-Range: bytes 0-3/9\r\n\r\nbeep\
+                 \r\n--{boundary}\r\nContent-Range: bytes 5-8/9\r\n\r\nboop\
+                 \r\n--{boundary}--\r\n"
+            )
+        );
+    }
+
+    #[test]
+    fn unsatisfiable_request() {
+        let res = request(&[("range", "bytes=10-")]);
+        assert_eq!(res.status(), 416);
+        assert_eq!(
+            res.headers().get("Content-Range"),
+            Some(&HeaderValue::from_static("bytes */9"))
+        );
+    }
+
     #[test]
     fn invalid_request() {
-        let range = HeaderValue::from_static("bytes=meow");
-        let data = Bytes::from_static(b"beep boop");
-        let res = Response::builder().header("Accept-Ranges", "bytes");
-        let res = ranged_response(res, &data, &range);
-        assert_eq!(res.unwrap().status(), 416);
+        let res = request(&[("range", "bytes=meow")]);
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers().get("Content-Range"), None);
+        assert_eq!(res.body().size_hint().exact(), Some(9));
+    }
+
+    #[test]
+    fn if_range() {
+        let res = request(&[("range", "bytes=3-5"), ("if-range", "\"fox\"")]);
+        assert_eq!(res.status(), 206);
+        let res = request(&[
+            ("range", "bytes=3-5"),
+            ("if-range", "Tue, 15 Nov 1994 08:12:31 GMT"),
+        ]);
+        assert_eq!(res.status(), 206);
+
+        let res = request(&[("range", "bytes=3-5"), ("if-range", "\"wolf\"")]);
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body().size_hint().exact(), Some(9));
+        let res = request(&[("range", "bytes=3-5"), ("if-range", "W/\"fox\"")]);
+        assert_eq!(res.status(), 200);
+
+        let res = request(&[]);
+        assert_eq!(res.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn streamed_request() {
+        let respond = |range| {
+            let body = Chunks {
+                chunks: [&b"be"[..], b"ep", b" b", b"oo", b"p"]
+                    .map(Bytes::from_static)
+                    .into(),
+                remaining: 9,
+            };
+            streamed_response(Response::builder(), body, &HeaderValue::from_static(range)).unwrap()
+        };
+
+        let res = respond("bytes=3-6");
+        assert_eq!(res.status(), 206);
+        assert_eq!(
+            res.headers().get("Content-Range"),
+            Some(&HeaderValue::from_static("bytes 3-6/9"))
+        );
+        assert_eq!(res.body().size_hint().exact(), Some(4));
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, Bytes::from_static(b"p bo"));
+
+        let res = respond("bytes=-1");
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, Bytes::from_static(b"p"));
+
+        assert_eq!(respond("bytes=20-").status(), 416);
+
+        // not worth the trouble
+        let res = respond("bytes=0-1,4-5");
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body().size_hint().exact(), Some(9));
     }
 }
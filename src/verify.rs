@@ -0,0 +1,277 @@
+use crate::{
+    cache::{CacheStore, Meta},
+    hclient::Client,
+    metrics::Metrics,
+};
+use http_body_util::BodyExt;
+use hyper::{body::Bytes, HeaderMap};
+use std::sync::Arc;
+
+/// checks extensions against the md5 sidecars tiny core publishes
+/// next to them before letting them into the cache
+pub struct Verifier {
+    mirrors: Vec<String>,
+    client: Arc<Client>,
+    cachestore: Arc<CacheStore>,
+    metrics: Arc<Metrics>,
+    /// cache extensions that have no usable sidecar anyway
+    allow_missing: bool,
+}
+
+/// the uri of the md5 sidecar for uri, if it should have one
+pub fn sidecar(uri: &str) -> Option<String> {
+    uri.ends_with(".tcz").then(|| format!("{uri}.md5.txt"))
+}
+
+/// pull the hash out of a sidecar, they look like md5sum output
+fn parse_sum(sidecar: &[u8]) -> Option<String> {
+    let sum = std::str::from_utf8(sidecar)
+        .ok()?
+        .split_whitespace()
+        .next()?;
+    if sum.len() != 32 || !sum.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(sum.to_ascii_lowercase())
+}
+
+/// get path from mirror, following any redirects
+async fn fetch(client: &Arc<Client>, mirror: &String, path: &str) -> Option<(Bytes, Meta)> {
+    let (res, _) = client
+        .try_get(std::slice::from_ref(mirror), path, &HeaderMap::new())
+        .await?;
+    let meta = Meta::from_headers(res.headers());
+    let content = res.into_body().collect().await.ok()?.to_bytes();
+    Some((content, meta))
+}
+
+impl Verifier {
+    pub fn new(
+        mirrors: Vec<String>,
+        client: Arc<Client>,
+        cachestore: Arc<CacheStore>,
+        metrics: Arc<Metrics>,
+        allow_missing: bool,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            mirrors,
+            client,
+            cachestore,
+            metrics,
+            allow_missing,
+        })
+    }
+
+    /// find the expected md5 for uri, preferring a fresh cached
+    /// sidecar unless told not to, and otherwise asking the mirror
+    /// the object came from
+    async fn expected(&self, sidecar: &str, mindex: usize, cached: bool) -> Option<String> {
+        if cached {
+            let object = self.cachestore.get(sidecar).await;
+            if let Some(sum) = object
+                .filter(|o| !o.stale)
+                .and_then(|o| parse_sum(&o.content))
+            {
+                return Some(sum);
+            }
+        }
+
+        let (content, _) = fetch(&self.client, self.mirrors.get(mindex)?, sidecar).await?;
+        parse_sum(&content)
+    }
+
+    async fn check(&self, uri: &str, content: &Bytes, mindex: usize) -> bool {
+        let Some(sidecar) = sidecar(uri) else {
+            return true;
+        };
+
+        let content = content.clone();
+        let digest = tokio::task::spawn_blocking(move || md5::compute(&content));
+        let Ok(digest) = digest.await.map(|d| format!("{d:x}")) else {
+            return false;
+        };
+
+        // the cached sidecar may be for an older version than the
+        // mirror now has, so ask it before calling it a mismatch
+        let mut found = false;
+        for cached in [true, false] {
+            let Some(expected) = self.expected(&sidecar, mindex, cached).await else {
+                continue;
+            };
+            if digest == expected {
+                return true;
+            }
+            found = true;
+        }
+
+        if !found {
+            // nothing to check against, which is only fine for
+            // mirrors known not to publish sidecars
+            #[cfg(feature = "log")]
+            eprintln!("no md5 sidecar for {} from mirror {}", uri, mindex);
+
+            self.metrics.trace_md5_missing();
+            return self.allow_missing;
+        }
+
+        #[cfg(feature = "log")]
+        eprintln!("md5 mismatch for {} from mirror {}", uri, mindex);
+
+        self.metrics.trace_md5_mismatch();
+        false
+    }
+
+    /// cache content fetched from mirrors[mindex] if it matches
+    /// its sidecar, otherwise retry with the following mirrors
+    pub async fn admit(self: Arc<Self>, uri: String, content: Bytes, meta: Meta, mindex: usize) {
+        if self.check(&uri, &content, mindex).await {
+            self.cachestore.insert_nonblocking(uri, content, meta);
+            return;
+        }
+
+        for (i, m) in self.mirrors.iter().enumerate().skip(mindex + 1) {
+            let Some((content, meta)) = fetch(&self.client, m, &uri).await else {
+                continue;
+            };
+            if self.check(&uri, &content, i).await {
+                let meta = Meta {
+                    mirror: Some(i),
+                    ..meta
+                };
+                self.cachestore.insert_nonblocking(uri, content, meta);
+                return;
+            }
+        }
+
+        #[cfg(feature = "log")]
+        eprintln!("not caching {} since no mirror had a good copy", uri);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::verify::*;
+
+    #[test]
+    fn sidecars() {
+        assert_eq!(
+            sidecar("/10.x/x86/tcz/sed.tcz").unwrap(),
+            "/10.x/x86/tcz/sed.tcz.md5.txt"
+        );
+        assert_eq!(sidecar("/10.x/x86/tcz/sed.tcz.dep"), None);
+
+        assert_eq!(
+            parse_sum(b"7D3A7C3F4AE5C3D0A0B7A53FBEB0CE0C  sed.tcz\n").unwrap(),
+            "7d3a7c3f4ae5c3d0a0b7a53fbeb0ce0c"
+        );
+        assert_eq!(parse_sum(b"<html>knot found</html>"), None);
+        assert_eq!(parse_sum(b""), None);
+    }
+
+    #[tokio::test]
+    async fn cached_sidecar() {
+        let metrics = Metrics::new();
+        let cachestore = CacheStore::new(None, None, None, Arc::clone(&metrics));
+        let client = Client::new(Default::default(), Arc::clone(&metrics));
+        let verifier = Verifier::new(
+            vec![],
+            client,
+            Arc::clone(&cachestore),
+            Arc::clone(&metrics),
+            false,
+        );
+
+        let content = Bytes::from_static(b"you wouldn't download a fox");
+        let sum = format!("{:x}  fox.tcz\n", md5::compute(&content));
+        cachestore.insert("/fox.tcz.md5.txt".to_string(), sum.into(), Meta::default());
+
+        Arc::clone(&verifier)
+            .admit("/fox.tcz".to_string(), content.clone(), Meta::default(), 0)
+            .await;
+        assert_eq!(cachestore.get("/fox.tcz").await.unwrap().content, content);
+
+        verifier
+            .admit(
+                "/fox.tcz".to_string(),
+                Bytes::from_static(b"you wouldn't download a wolf"),
+                Meta::default(),
+                0,
+            )
+            .await;
+        assert!(metrics.output().contains("md5_mismatches 1\n"));
+        // the good copy should still be there
+        assert_eq!(cachestore.get("/fox.tcz").await.unwrap().content, content);
+    }
+
+    #[tokio::test]
+    async fn missing_sidecar() {
+        let metrics = Metrics::new();
+        let cachestore = CacheStore::new(None, None, None, Arc::clone(&metrics));
+        let client = Client::new(Default::default(), Arc::clone(&metrics));
+        let content = Bytes::from_static(b"you wouldn't download a fox");
+
+        for allow_missing in [false, true] {
+            let verifier = Verifier::new(
+                vec![],
+                Arc::clone(&client),
+                Arc::clone(&cachestore),
+                Arc::clone(&metrics),
+                allow_missing,
+            );
+            verifier
+                .admit("/fox.tcz".to_string(), content.clone(), Meta::default(), 0)
+                .await;
+            assert_eq!(
+                cachestore.get("/fox.tcz").await.is_some(),
+                allow_missing,
+                "{allow_missing}"
+            );
+        }
+        assert!(metrics.output().contains("md5_missing 2\n"));
+    }
+
+    #[tokio::test]
+    async fn redirected_sidecar() {
+        use http_body_util::Full;
+        use hyper::Response;
+
+        let content = Bytes::from_static(b"you wouldn't download a fox");
+        let sum = Bytes::from(format!("{:x}  fox.tcz\n", md5::compute(&content)));
+        let mirror = crate::hclient::test_server(hyper::Version::HTTP_11, move |req| {
+            let res = match req.uri().path() {
+                "/fox.tcz.md5.txt" => Response::builder()
+                    .status(301)
+                    .header("location", "/cdn/fox.tcz.md5.txt")
+                    .body(Full::new(Bytes::new())),
+                "/cdn/fox.tcz.md5.txt" => Response::builder().body(Full::new(sum.clone())),
+                _ => Response::builder()
+                    .status(404)
+                    .body(Full::new(Bytes::new())),
+            };
+            async { res }
+        })
+        .await;
+
+        let metrics = Metrics::new();
+        let cachestore = CacheStore::new(None, None, None, Arc::clone(&metrics));
+        let client = Client::new(Default::default(), Arc::clone(&metrics));
+        let verifier = Verifier::new(
+            vec![mirror],
+            client,
+            Arc::clone(&cachestore),
+            Arc::clone(&metrics),
+            false,
+        );
+        // left over from an older version of it
+        let old = format!("{:x}  fox.tcz\n", md5::compute(b"an older fox"));
+        cachestore.insert("/fox.tcz.md5.txt".to_string(), old.into(), Meta::default());
+
+        verifier
+            .admit("/fox.tcz".to_string(), content.clone(), Meta::default(), 0)
+            .await;
+        assert_eq!(cachestore.get("/fox.tcz").await.unwrap().content, content);
+        let output = metrics.output();
+        assert!(output.contains("md5_missing 0\n"));
+        assert!(output.contains("md5_mismatches 0\n"));
+    }
+}